@@ -0,0 +1,137 @@
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BackupEntry {
+    original: PathBuf,
+    backup: PathBuf,
+}
+
+/// Paths moved aside by the `remove_paths` step instead of being deleted.
+/// They are put back by `restore` if the update fails and dropped by `discard` once it succeeds.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Backup {
+    root: PathBuf,
+    entries: Vec<BackupEntry>,
+}
+
+fn remove_path(path: &Path) -> std::io::Result<()> {
+    if path.is_dir() && !path.is_symlink() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    }
+}
+
+fn device_of(path: &Path) -> anyhow::Result<u64> {
+    fs::symlink_metadata(path)
+        .map(|m| m.dev())
+        .map_err(|e| anyhow::anyhow!("Failed to stat {}: {}", path.display(), e))
+}
+
+impl Backup {
+    /// Renames every existing path from `paths` into a new directory under `backup_dir`.
+    /// Renaming only works within one filesystem, so this is checked before anything is moved.
+    pub fn move_aside(paths: &[PathBuf], backup_dir: &Path) -> anyhow::Result<Backup> {
+        let root = backup_dir.join(format!("backup-{}", Utc::now().format("%Y%m%d%H%M%S")));
+        fs::create_dir_all(&root).map_err(|e| {
+            anyhow::anyhow!("Failed to create backup dir {}: {}", root.display(), e)
+        })?;
+        let backup_device = device_of(&root)?;
+
+        let existing = paths
+            .iter()
+            .filter(|p| p.symlink_metadata().is_ok())
+            .collect::<Vec<_>>();
+        for path in &existing {
+            if device_of(path)? != backup_device {
+                return Err(anyhow::anyhow!(
+                    "Cannot move {} aside: {} is on a different filesystem",
+                    path.display(),
+                    backup_dir.display()
+                ));
+            }
+        }
+
+        let mut backup = Backup {
+            root,
+            entries: Vec::new(),
+        };
+        for (idx, path) in existing.into_iter().enumerate() {
+            let name = path
+                .file_name()
+                .map(|n| n.to_string_lossy().into_owned())
+                .unwrap_or_else(|| "root".into());
+            let target = backup.root.join(format!("{}-{}", idx, name));
+            log::info!("Moving {} aside to {}", path.display(), target.display());
+            if let Err(e) = fs::rename(path, &target) {
+                log::error!("Failed to move {} aside: {}", path.display(), e);
+                if let Err(restore_err) = backup.restore() {
+                    log::error!("Failed to restore partial backup: {}", restore_err);
+                }
+                return Err(anyhow::anyhow!(
+                    "Failed to move {} aside: {}",
+                    path.display(),
+                    e
+                ));
+            }
+            backup.entries.push(BackupEntry {
+                original: path.clone(),
+                backup: target,
+            });
+        }
+        Ok(backup)
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+
+    /// Puts every moved path back, replacing whatever was written in its place since
+    pub fn restore(&self) -> anyhow::Result<()> {
+        for entry in self.entries.iter().rev() {
+            // already put back by a restore the updater was stopped in
+            if entry.backup.symlink_metadata().is_err() {
+                log::info!("Already restored: {}", entry.original.display());
+                continue;
+            }
+            if entry.original.symlink_metadata().is_ok() {
+                log::info!("Removing partial data: {}", entry.original.display());
+                remove_path(&entry.original).map_err(|e| {
+                    anyhow::anyhow!(
+                        "Failed to remove partial data {}: {}",
+                        entry.original.display(),
+                        e
+                    )
+                })?;
+            }
+            if let Some(parent) = entry.original.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            log::info!(
+                "Restoring {} from {}",
+                entry.original.display(),
+                entry.backup.display()
+            );
+            fs::rename(&entry.backup, &entry.original).map_err(|e| {
+                anyhow::anyhow!("Failed to restore {}: {}", entry.original.display(), e)
+            })?;
+        }
+        let _ = fs::remove_dir(&self.root);
+        Ok(())
+    }
+
+    /// Deletes the moved data for good
+    pub fn discard(&self) -> anyhow::Result<()> {
+        if !self.root.exists() {
+            return Ok(());
+        }
+        log::info!("Removing backup: {}", self.root.display());
+        fs::remove_dir_all(&self.root)
+            .map_err(|e| anyhow::anyhow!("Failed to remove backup {}: {}", self.root.display(), e))
+    }
+}
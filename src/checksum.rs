@@ -0,0 +1,154 @@
+use std::fmt;
+
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::remote::fetch_text;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChecksumAlgorithm {
+    Sha256,
+    Blake3,
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChecksumAlgorithm::Sha256 => f.write_str("sha256"),
+            ChecksumAlgorithm::Blake3 => f.write_str("blake3"),
+        }
+    }
+}
+
+/// Digest the archive has to match, written as `sha256:<hex>` or `blake3:<hex>`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct ExpectedChecksum {
+    pub algorithm: ChecksumAlgorithm,
+    pub hex: String,
+}
+
+impl ExpectedChecksum {
+    pub fn parse(checksum: &str) -> anyhow::Result<Self> {
+        let (algorithm, hex) = checksum
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("checksum has to be sha256:<hex> or blake3:<hex>"))?;
+        let algorithm = match algorithm {
+            "sha256" => ChecksumAlgorithm::Sha256,
+            "blake3" => ChecksumAlgorithm::Blake3,
+            other => return Err(anyhow::anyhow!("Unsupported checksum algorithm: {}", other)),
+        };
+        let hex = hex.trim().to_ascii_lowercase();
+        if hex.len() != 64 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(anyhow::anyhow!("Invalid {} checksum: {}", algorithm, hex));
+        }
+        Ok(Self { algorithm, hex })
+    }
+}
+
+/// Outcome of the checksum check, shown in `/progress`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChecksumResult {
+    pub expected: ExpectedChecksum,
+    pub actual: Option<String>,
+    pub verified: Option<bool>,
+}
+
+/// Returned when the archive does not match the expected checksum,
+/// so the failure is not retried and can be told apart from download errors
+#[derive(Debug, thiserror::Error)]
+#[error("Checksum verification failed for {url}: expected {algorithm}:{expected}, got {actual}")]
+pub struct ChecksumError {
+    pub url: String,
+    pub algorithm: ChecksumAlgorithm,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Parses a `.sha256` file, which contains the hex digest optionally followed by a file name
+pub fn parse_sidecar(contents: &str) -> anyhow::Result<ExpectedChecksum> {
+    let hex = contents
+        .split_whitespace()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("Checksum file is empty"))?;
+    ExpectedChecksum::parse(&format!("sha256:{}", hex))
+}
+
+pub fn sidecar_url(url: &str) -> String {
+    format!("{}.sha256", url)
+}
+
+/// Checksum configured for the profile, or read from the sidecar file when `sidecar` is set
+pub fn resolve_expected_checksum(
+    url: &str,
+    checksum: &Option<String>,
+    sidecar: bool,
+) -> anyhow::Result<Option<ExpectedChecksum>> {
+    match checksum {
+        Some(checksum) => Ok(Some(ExpectedChecksum::parse(checksum)?)),
+        None if sidecar => Ok(Some(parse_sidecar(&fetch_text(&sidecar_url(url))?)?)),
+        None => Ok(None),
+    }
+}
+
+pub enum Hasher {
+    Sha256(Sha256),
+    Blake3(Box<blake3::Hasher>),
+}
+
+impl Hasher {
+    pub fn new(algorithm: ChecksumAlgorithm) -> Self {
+        match algorithm {
+            ChecksumAlgorithm::Sha256 => Hasher::Sha256(Sha256::new()),
+            ChecksumAlgorithm::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+        }
+    }
+
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Sha256(hasher) => hasher.update(data),
+            Hasher::Blake3(hasher) => {
+                hasher.update(data);
+            }
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        match self {
+            Hasher::Sha256(hasher) => format!("{:x}", hasher.finalize()),
+            Hasher::Blake3(hasher) => hasher.finalize().to_hex().to_string(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HEX: &str = "9f86d081884c7d659a2feaa0c55ad015a3bf4f1b2b0b822cd15d6c15b0f00a08";
+
+    #[test]
+    fn parses_algorithm_and_normalizes_hex() {
+        let parsed = ExpectedChecksum::parse(&format!("sha256: {} ", HEX.to_uppercase())).unwrap();
+        assert_eq!(parsed.algorithm, ChecksumAlgorithm::Sha256);
+        assert_eq!(parsed.hex, HEX);
+        let parsed = ExpectedChecksum::parse(&format!("blake3:{}", HEX)).unwrap();
+        assert_eq!(parsed.algorithm, ChecksumAlgorithm::Blake3);
+    }
+
+    #[test]
+    fn rejects_malformed_checksums() {
+        assert!(ExpectedChecksum::parse(HEX).is_err());
+        assert!(ExpectedChecksum::parse(&format!("md5:{}", HEX)).is_err());
+        assert!(ExpectedChecksum::parse(&format!("sha256:{}", &HEX[..63])).is_err());
+        assert!(ExpectedChecksum::parse(&format!("sha256:{}z", &HEX[..63])).is_err());
+    }
+
+    #[test]
+    fn reads_digest_from_sidecar() {
+        let parsed = parse_sidecar(&format!("{}  erigon.tar.lz4\n", HEX)).unwrap();
+        assert_eq!(parsed.hex, HEX);
+        assert!(parse_sidecar("\n").is_err());
+    }
+}
@@ -0,0 +1,188 @@
+use std::io::Read;
+use std::os::unix::process::CommandExt;
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
+use serde::Serialize;
+
+/// Only the tail of a command's output is kept, so a chatty script cannot blow up `/progress`
+const MAX_CAPTURED_OUTPUT: usize = 16 * 1024;
+
+/// How long output is still collected after the command exited. A process it left running
+/// in the background can hold the pipes open indefinitely.
+const OUTPUT_GRACE: Duration = Duration::from_secs(2);
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct CommandOutput {
+    pub command: Vec<String>,
+    pub exit_code: Option<i32>,
+    pub timed_out: bool,
+    pub duration_secs: f64,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl CommandOutput {
+    pub fn success(&self) -> bool {
+        !self.timed_out && self.exit_code == Some(0)
+    }
+
+    pub fn describe_failure(&self) -> String {
+        if self.timed_out {
+            format!(
+                "Command {:?} timed out after {:.1}s",
+                self.command, self.duration_secs
+            )
+        } else {
+            format!(
+                "Command {:?} failed with exit code {:?}: {}",
+                self.command,
+                self.exit_code,
+                self.stderr.trim()
+            )
+        }
+    }
+}
+
+fn read_tail(mut reader: impl Read) -> String {
+    let mut buf = Vec::new();
+    let _ = reader.read_to_end(&mut buf);
+    let start = buf.len().saturating_sub(MAX_CAPTURED_OUTPUT);
+    String::from_utf8_lossy(&buf[start..]).into_owned()
+}
+
+fn spawn_reader(reader: impl Read + Send + 'static) -> Receiver<String> {
+    let (sender, receiver) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = sender.send(read_tail(reader));
+    });
+    receiver
+}
+
+/// Runs `command` (program followed by its arguments, no shell involved),
+/// killing it and everything it started if it does not exit within `timeout`
+pub fn run_command(command: &[String], timeout: Option<Duration>) -> anyhow::Result<CommandOutput> {
+    let (program, args) = command
+        .split_first()
+        .ok_or_else(|| anyhow::anyhow!("Empty command"))?;
+    log::info!("Running command: {:?}", command);
+    let started = Instant::now();
+    let mut child = Command::new(program)
+        .args(args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        // own process group, so a timeout also kills the processes the command started
+        .process_group(0)
+        .spawn()
+        .map_err(|e| anyhow::anyhow!("Failed to spawn command {:?}: {}", command, e))?;
+
+    let stdout = child.stdout.take().map(spawn_reader);
+    let stderr = child.stderr.take().map(spawn_reader);
+
+    let mut timed_out = false;
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if timeout.map(|t| started.elapsed() > t).unwrap_or(false) {
+            log::warn!("Command {:?} timed out, killing it", command);
+            timed_out = true;
+            if let Err(e) = killpg(Pid::from_raw(child.id() as i32), Signal::SIGKILL) {
+                log::warn!("Failed to kill process group of {:?}: {}", command, e);
+                let _ = child.kill();
+            }
+            break child.wait()?;
+        }
+        thread::sleep(Duration::from_millis(100));
+    };
+
+    let output_deadline = Instant::now() + OUTPUT_GRACE;
+    let collect = |receiver: Option<Receiver<String>>| {
+        receiver
+            .and_then(|receiver| {
+                receiver
+                    .recv_timeout(output_deadline.saturating_duration_since(Instant::now()))
+                    .ok()
+            })
+            .unwrap_or_default()
+    };
+    Ok(CommandOutput {
+        command: command.to_vec(),
+        exit_code: status.code(),
+        timed_out,
+        duration_secs: started.elapsed().as_secs_f64(),
+        stdout: collect(stdout),
+        stderr: collect(stderr),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    fn sh(script: &str) -> Vec<String> {
+        vec!["sh".into(), "-c".into(), script.into()]
+    }
+
+    /// Gone, or a zombie waiting for a parent that is not the test
+    fn is_dead(pid: &str) -> bool {
+        match fs::read_to_string(format!("/proc/{}/stat", pid)) {
+            Ok(stat) => stat
+                .rsplit(')')
+                .next()
+                .map(|rest| rest.trim_start().starts_with('Z'))
+                .unwrap_or(false),
+            Err(_) => true,
+        }
+    }
+
+    #[test]
+    fn captures_output_and_exit_code() {
+        let output = run_command(&sh("echo out; echo err >&2; exit 3"), None).unwrap();
+        assert_eq!(output.exit_code, Some(3));
+        assert!(!output.success());
+        assert_eq!(output.stdout, "out\n");
+        assert_eq!(output.stderr, "err\n");
+    }
+
+    #[test]
+    fn rejects_empty_command() {
+        assert!(run_command(&[], None).is_err());
+    }
+
+    #[test]
+    fn timeout_kills_the_whole_process_group() {
+        let started = Instant::now();
+        let output = run_command(
+            &sh("sleep 30 & echo $!; wait"),
+            Some(Duration::from_millis(500)),
+        )
+        .unwrap();
+        assert!(output.timed_out);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        let background = output.stdout.trim();
+        assert!(!background.is_empty());
+        thread::sleep(Duration::from_millis(200));
+        assert!(
+            is_dead(background),
+            "background process {} survived",
+            background
+        );
+    }
+
+    #[test]
+    fn does_not_wait_for_pipes_held_by_background_processes() {
+        let started = Instant::now();
+        let output = run_command(&sh("sleep 5 & echo started"), None).unwrap();
+        assert!(output.success());
+        assert!(started.elapsed() < Duration::from_secs(4));
+    }
+}
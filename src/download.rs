@@ -0,0 +1,480 @@
+use std::cell::Cell;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use chrono::{DateTime, Utc};
+use pipe_downloader::pipe_downloader::{PipeDownloader, PipeDownloaderOptions};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{ChecksumError, ChecksumResult};
+use crate::stream::{download_and_hash, OnInterrupted, StreamControl, StreamProgress};
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_initial_backoff_secs() -> u64 {
+    10
+}
+
+fn default_max_backoff_secs() -> u64 {
+    300
+}
+
+fn default_jitter() -> f64 {
+    0.2
+}
+
+/// How the `download` step retries a failed download:
+///
+/// ```toml
+/// [profiles.erigon.download_retry]
+/// max_attempts = 5
+/// initial_backoff_secs = 10
+/// max_backoff_secs = 300
+/// jitter = 0.2
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RetryPolicy {
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    #[serde(default = "default_initial_backoff_secs")]
+    pub initial_backoff_secs: u64,
+    #[serde(default = "default_max_backoff_secs")]
+    pub max_backoff_secs: u64,
+    /// Backoff is randomly scaled by up to this fraction in both directions
+    #[serde(default = "default_jitter")]
+    pub jitter: f64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: default_max_attempts(),
+            initial_backoff_secs: default_initial_backoff_secs(),
+            max_backoff_secs: default_max_backoff_secs(),
+            jitter: default_jitter(),
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.max_attempts == 0 {
+            return Err(anyhow::anyhow!(
+                "download_retry.max_attempts has to be at least 1"
+            ));
+        }
+        if !(0.0..=1.0).contains(&self.jitter) {
+            return Err(anyhow::anyhow!(
+                "download_retry.jitter has to be between 0 and 1"
+            ));
+        }
+        Ok(())
+    }
+
+    /// Delay before attempt number `attempt + 1`
+    fn backoff(&self, attempt: u32) -> Duration {
+        let exponential = self
+            .initial_backoff_secs
+            .saturating_mul(1u64 << (attempt - 1).min(20))
+            .min(self.max_backoff_secs) as f64;
+        let factor = if self.jitter > 0.0 {
+            1.0 + rand::thread_rng().gen_range(-self.jitter..=self.jitter)
+        } else {
+            1.0
+        };
+        Duration::from_secs_f64(exponential * factor)
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DownloadAttempt {
+    pub attempt: u32,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Compressed bytes received by the attempt
+    pub bytes_downloaded: Option<u64>,
+    pub error_message: Option<String>,
+    /// Byte offset a verified stream continued from after its connection broke,
+    /// unset for attempts that started from the beginning
+    pub resumed_from: Option<u64>,
+}
+
+/// `total_downloaded` counts from the start of the archive, a resumed attempt only gets
+/// the bytes past its offset
+fn finish_attempt(
+    attempts: &Mutex<Vec<DownloadAttempt>>,
+    total_downloaded: u64,
+    error_message: Option<String>,
+) {
+    if let Some(last) = attempts.lock().unwrap().last_mut() {
+        last.finished_at = Some(Utc::now());
+        last.bytes_downloaded =
+            Some(total_downloaded.saturating_sub(last.resumed_from.unwrap_or(0)));
+        last.error_message = error_message;
+    }
+}
+
+/// Sleeps the backoff after attempt number `attempt`, returns false if the download was aborted meanwhile
+fn wait_before_retry(
+    policy: &RetryPolicy,
+    attempt: u32,
+    control: &StreamControl,
+    error: &dyn std::fmt::Display,
+) -> bool {
+    let backoff = policy.backoff(attempt);
+    log::warn!(
+        "Download attempt {}/{} failed, retrying in {:.1}s: {}",
+        attempt,
+        policy.max_attempts,
+        backoff.as_secs_f64(),
+        error
+    );
+    let wait_started = Instant::now();
+    while wait_started.elapsed() < backoff {
+        if control.abort_requested.load(Ordering::SeqCst) {
+            return false;
+        }
+        thread::sleep(Duration::from_millis(500));
+    }
+    true
+}
+
+fn wait_for_download(downloader: &Arc<Mutex<PipeDownloader>>) -> anyhow::Result<()> {
+    log::info!("Start download");
+    match downloader.lock().unwrap().start_download() {
+        Ok(_) => {}
+        Err(e) => {
+            log::error!("Error started downloading: {}", e);
+            return Err(anyhow::anyhow!("Error started downloading: {}", e));
+        }
+    };
+
+    while !downloader.lock().unwrap().is_finished() {
+        thread::sleep(Duration::from_secs(1));
+    }
+
+    log::info!("Download finished");
+
+    if let Some(error_message) = downloader.lock().unwrap().get_progress().error_message {
+        log::error!("Error downloading: {}", error_message);
+        return Err(anyhow::anyhow!(
+            "Download failed with error: {}",
+            error_message
+        ));
+    }
+    Ok(())
+}
+
+/// Runs one download. With an expected checksum the archive is fetched and unpacked in a
+/// single stream that is hashed on the way, pipe_downloader does not expose the raw bytes.
+fn download_once(
+    url: &str,
+    output_dir: &Path,
+    downloader: &Arc<Mutex<PipeDownloader>>,
+    checksum: &Arc<Mutex<Option<ChecksumResult>>>,
+    control: &StreamControl,
+    on_interrupted: OnInterrupted<'_>,
+) -> anyhow::Result<()> {
+    let expected = checksum
+        .lock()
+        .unwrap()
+        .as_ref()
+        .map(|c| c.expected.clone());
+    let expected = match expected {
+        Some(expected) => expected,
+        None => return wait_for_download(downloader),
+    };
+    let actual = download_and_hash(url, output_dir, expected.algorithm, control, on_interrupted)?;
+    let verified = actual == expected.hex;
+    if let Some(result) = checksum.lock().unwrap().as_mut() {
+        result.actual = Some(actual.clone());
+        result.verified = Some(verified);
+    }
+    if !verified {
+        log::error!(
+            "Checksum mismatch for {}: expected {}, got {}",
+            url,
+            expected.hex,
+            actual
+        );
+        return Err(ChecksumError {
+            url: url.to_string(),
+            algorithm: expected.algorithm,
+            expected: expected.hex,
+            actual,
+        }
+        .into());
+    }
+    log::info!("Checksum verified: {}:{}", expected.algorithm, actual);
+    Ok(())
+}
+
+/// Downloads and unpacks the archive, retrying according to `policy`.
+/// A checksum mismatch is returned as `ChecksumError` right away, retrying would fetch the same data.
+/// When the verified stream loses its connection, the next attempt asks for the rest with a
+/// `Range` request and keeps feeding the same hasher and decoder. pipe_downloader does not
+/// expose the offset it reached, so its attempts start over and overwrite what the previous
+/// one unpacked, as does a stream whose server cannot resume.
+pub fn download_with_retry(
+    url: &str,
+    output_dir: &Path,
+    policy: &RetryPolicy,
+    downloader: &Arc<Mutex<PipeDownloader>>,
+    attempts: &Arc<Mutex<Vec<DownloadAttempt>>>,
+    checksum: &Arc<Mutex<Option<ChecksumResult>>>,
+    control: &StreamControl,
+) -> anyhow::Result<()> {
+    let verified_stream = checksum.lock().unwrap().is_some();
+    // resumed attempts are counted inside the stream, the loop below continues from them
+    let attempt = Cell::new(1);
+    let resume = |err: &io::Error, offset: u64| {
+        let current = attempt.get();
+        if control.abort_requested.load(Ordering::SeqCst) || current >= policy.max_attempts {
+            return false;
+        }
+        finish_attempt(attempts, offset, Some(err.to_string()));
+        if !wait_before_retry(policy, current, control, err) {
+            return false;
+        }
+        attempt.set(current + 1);
+        attempts.lock().unwrap().push(DownloadAttempt {
+            attempt: current + 1,
+            started_at: Utc::now(),
+            finished_at: None,
+            bytes_downloaded: None,
+            error_message: None,
+            resumed_from: Some(offset),
+        });
+        true
+    };
+    loop {
+        if attempt.get() > 1 {
+            // the previous downloader cannot be started again
+            *downloader.lock().unwrap() =
+                PipeDownloader::new(url, output_dir, PipeDownloaderOptions::from_env());
+        }
+        // a GET that fails must not report the bytes of the previous attempt again
+        *control.progress.lock().unwrap() = StreamProgress::default();
+        attempts.lock().unwrap().push(DownloadAttempt {
+            attempt: attempt.get(),
+            started_at: Utc::now(),
+            finished_at: None,
+            bytes_downloaded: None,
+            error_message: None,
+            resumed_from: None,
+        });
+        let result = download_once(url, output_dir, downloader, checksum, control, &resume);
+        let total_downloaded = if verified_stream {
+            control.progress.lock().unwrap().downloaded
+        } else {
+            downloader.lock().unwrap().get_progress().total_downloaded as u64
+        };
+        finish_attempt(
+            attempts,
+            total_downloaded,
+            result.as_ref().err().map(|e| e.to_string()),
+        );
+        let err = match result {
+            Ok(()) => return Ok(()),
+            Err(err) if err.is::<ChecksumError>() => return Err(err),
+            Err(err) => err,
+        };
+        if control.abort_requested.load(Ordering::SeqCst) {
+            return Err(anyhow::anyhow!("Download aborted: {}", err));
+        }
+        if attempt.get() >= policy.max_attempts {
+            return Err(anyhow::anyhow!(
+                "Download failed after {} attempts: {}",
+                attempt.get(),
+                err
+            ));
+        }
+        if !wait_before_retry(policy, attempt.get(), control, &err) {
+            return Err(anyhow::anyhow!("Download aborted: {}", err));
+        }
+        attempt.set(attempt.get() + 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+
+    use super::*;
+    use crate::checksum::{ChecksumAlgorithm, ExpectedChecksum, Hasher};
+
+    fn no_backoff(max_attempts: u32) -> RetryPolicy {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff_secs: 0,
+            max_backoff_secs: 0,
+            jitter: 0.0,
+        }
+    }
+
+    /// Request line and headers, lowercased
+    fn read_request(reader: &mut impl BufRead) -> Vec<String> {
+        let mut lines = Vec::new();
+        loop {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            let line = line.trim().to_ascii_lowercase();
+            if line.is_empty() {
+                return lines;
+            }
+            lines.push(line);
+        }
+    }
+
+    #[test]
+    fn failed_requests_do_not_repeat_previous_byte_counts() {
+        // nothing listens on the discard port, every GET fails before any byte arrives
+        let url = "http://127.0.0.1:9/erigon.tar.lz4";
+        let output_dir = Path::new("/nonexistent/pipe_updater");
+        let control = StreamControl::default();
+        control.progress.lock().unwrap().downloaded = 1000;
+        let checksum = Arc::new(Mutex::new(Some(ChecksumResult {
+            expected: ExpectedChecksum::parse(&format!("sha256:{}", "a".repeat(64))).unwrap(),
+            actual: None,
+            verified: None,
+        })));
+        let policy = no_backoff(2);
+        let downloader = Arc::new(Mutex::new(PipeDownloader::new(
+            url,
+            output_dir,
+            PipeDownloaderOptions::from_env(),
+        )));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let result = download_with_retry(
+            url,
+            output_dir,
+            &policy,
+            &downloader,
+            &attempts,
+            &checksum,
+            &control,
+        );
+        assert!(result.is_err());
+        let bytes = attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|attempt| attempt.bytes_downloaded)
+            .collect::<Vec<_>>();
+        assert_eq!(bytes, vec![Some(0), Some(0)]);
+    }
+
+    #[test]
+    fn resumes_a_broken_stream_with_a_range_request() {
+        let mut builder = tar::Builder::new(Vec::new());
+        let contents = (0..64 * 1024u32)
+            .map(|idx| (idx * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "chaindata/mdbx.dat", contents.as_slice())
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+        let half = archive.len() / 2;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/erigon.tar", listener.local_addr().unwrap());
+        let served = archive.clone();
+        let server = thread::spawn(move || {
+            // the first connection breaks halfway through the body
+            let (mut stream, _) = listener.accept().unwrap();
+            read_request(&mut BufReader::new(stream.try_clone().unwrap()));
+            write!(
+                stream,
+                "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nETag: \"v1\"\r\n\r\n",
+                served.len()
+            )
+            .unwrap();
+            stream.write_all(&served[..half]).unwrap();
+            drop(stream);
+
+            let (mut stream, _) = listener.accept().unwrap();
+            let request = read_request(&mut BufReader::new(stream.try_clone().unwrap()));
+            write!(
+                stream,
+                "HTTP/1.1 206 Partial Content\r\nContent-Length: {}\r\nContent-Range: bytes {}-{}/{}\r\nConnection: close\r\n\r\n",
+                served.len() - half,
+                half,
+                served.len() - 1,
+                served.len()
+            )
+            .unwrap();
+            stream.write_all(&served[half..]).unwrap();
+            request
+        });
+
+        let mut hasher = Hasher::new(ChecksumAlgorithm::Sha256);
+        hasher.update(&archive);
+        let checksum = Arc::new(Mutex::new(Some(ChecksumResult {
+            expected: ExpectedChecksum::parse(&format!("sha256:{}", hasher.finalize_hex()))
+                .unwrap(),
+            actual: None,
+            verified: None,
+        })));
+        let output_dir =
+            std::env::temp_dir().join(format!("pipe_updater_resume_{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output_dir);
+        let downloader = Arc::new(Mutex::new(PipeDownloader::new(
+            &url,
+            &output_dir,
+            PipeDownloaderOptions::from_env(),
+        )));
+        let attempts = Arc::new(Mutex::new(Vec::new()));
+        let result = download_with_retry(
+            &url,
+            &output_dir,
+            &no_backoff(2),
+            &downloader,
+            &attempts,
+            &checksum,
+            &StreamControl::default(),
+        );
+        let unpacked = std::fs::read(output_dir.join("chaindata/mdbx.dat"));
+        let _ = std::fs::remove_dir_all(&output_dir);
+        let resume_request = server.join().unwrap();
+
+        result.unwrap();
+        assert_eq!(unpacked.unwrap(), contents);
+        assert!(resume_request.contains(&format!("range: bytes={}-", half)));
+        assert!(resume_request.contains(&"if-range: \"v1\"".to_string()));
+        assert_eq!(
+            checksum.lock().unwrap().as_ref().unwrap().verified,
+            Some(true)
+        );
+        let attempts = attempts.lock().unwrap();
+        assert_eq!(
+            attempts
+                .iter()
+                .map(|attempt| (
+                    attempt.attempt,
+                    attempt.resumed_from,
+                    attempt.bytes_downloaded
+                ))
+                .collect::<Vec<_>>(),
+            vec![
+                (1, None, Some(half as u64)),
+                (2, Some(half as u64), Some((archive.len() - half) as u64)),
+            ]
+        );
+        assert!(attempts[0].error_message.is_some());
+        assert!(attempts[1].error_message.is_none());
+    }
+}
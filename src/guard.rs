@@ -0,0 +1,238 @@
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+
+/// Never removed and never an ancestor of a removed path
+const PROTECTED_PATHS: &[&str] = &[
+    "/", "/bin", "/boot", "/dev", "/etc", "/home", "/lib", "/lib64", "/proc", "/root", "/run",
+    "/sbin", "/sys", "/usr", "/var",
+];
+
+/// Nothing below these is removed, whatever the configured roots say
+const PROTECTED_TREES: &[&str] = &[
+    "/bin", "/boot", "/dev", "/etc", "/lib", "/lib64", "/proc", "/root", "/sbin", "/sys", "/usr",
+];
+
+const PASSWD: &str = "/etc/passwd";
+
+/// Resolves symlinks in `path`, paths that do not exist yet are resolved through
+/// their closest existing ancestor
+pub fn canonicalize_lenient(path: &Path) -> std::io::Result<PathBuf> {
+    let mut missing = Vec::new();
+    let mut existing = path;
+    loop {
+        match fs::canonicalize(existing) {
+            Ok(canonical) => {
+                return Ok(missing
+                    .iter()
+                    .rev()
+                    .fold(canonical, |acc: PathBuf, part| acc.join(part)))
+            }
+            Err(e) => match (existing.parent(), existing.file_name()) {
+                (Some(parent), Some(name)) => {
+                    missing.push(name.to_owned());
+                    existing = parent;
+                }
+                _ => return Err(e),
+            },
+        }
+    }
+}
+
+/// Home directories of all accounts, service accounts often live outside of `/home`
+/// (`/var/lib/erigon`). Entries without a usable home, such as `/` or relative paths, are skipped.
+fn passwd_homes(passwd: &str) -> Vec<PathBuf> {
+    passwd
+        .lines()
+        .filter(|line| !line.starts_with('#'))
+        .filter_map(|line| line.split(':').nth(5))
+        .map(PathBuf::from)
+        .filter(|home| home.is_absolute() && home.parent().is_some())
+        .collect()
+}
+
+/// A home directory or one of its ancestors, which would take the home with it
+fn is_home_dir(path: &Path, homes: &[PathBuf]) -> bool {
+    path.parent() == Some(Path::new("/home")) || homes.iter().any(|home| home.starts_with(path))
+}
+
+fn check_path(path: &Path, roots: &[PathBuf], homes: &[PathBuf]) -> Result<(), String> {
+    if path.as_os_str().is_empty() {
+        return Err("empty path".into());
+    }
+    let escapes = path
+        .components()
+        .any(|c| matches!(c, Component::ParentDir | Component::CurDir));
+    if !path.is_absolute() || escapes {
+        return Err("path has to be absolute and normalized".into());
+    }
+    let canonical = canonicalize_lenient(path).map_err(|e| e.to_string())?;
+    for checked in [path, canonical.as_path()] {
+        let protected = PROTECTED_PATHS
+            .iter()
+            .any(|p| Path::new(p).starts_with(checked))
+            || PROTECTED_TREES.iter().any(|p| checked.starts_with(p))
+            || is_home_dir(checked, homes);
+        if protected {
+            return Err(format!("{} is protected", checked.display()));
+        }
+    }
+    let under_root = roots.iter().any(|root| {
+        canonicalize_lenient(root)
+            .map(|root| canonical.starts_with(root))
+            .unwrap_or(false)
+    });
+    if !under_root {
+        return Err(format!(
+            "resolves to {}, outside of removal roots",
+            canonical.display()
+        ));
+    }
+    Ok(())
+}
+
+/// Fails with every rejected path if any of `paths` is protected or does not resolve
+/// to a location under one of `roots`
+pub fn check_removal_paths(paths: &[PathBuf], roots: &[PathBuf]) -> anyhow::Result<()> {
+    if paths.is_empty() {
+        return Ok(());
+    }
+    if roots.is_empty() {
+        return Err(anyhow::anyhow!(
+            "Refusing to remove {:?}: no removal_roots configured",
+            paths
+        ));
+    }
+    let homes = match fs::read_to_string(PASSWD) {
+        // compared with both the configured and the resolved path, like the protected paths
+        Ok(passwd) => passwd_homes(&passwd)
+            .into_iter()
+            .flat_map(|home| [canonicalize_lenient(&home).ok(), Some(home)])
+            .flatten()
+            .collect(),
+        Err(e) => {
+            log::warn!("Failed to read {}, only /home is protected: {}", PASSWD, e);
+            vec![]
+        }
+    };
+    let rejected = paths
+        .iter()
+        .filter_map(|path| {
+            check_path(path, roots, &homes)
+                .err()
+                .map(|e| format!("{:?} ({})", path, e))
+        })
+        .collect::<Vec<_>>();
+    if rejected.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Refusing to remove paths: {}",
+            rejected.join(", ")
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// Fresh directory under the system temp dir, resolved so it compares with canonical paths
+    fn temp_root(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "pipe_updater_guard_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::canonicalize(&dir).unwrap()
+    }
+
+    #[test]
+    fn rejects_filesystem_root() {
+        assert!(check_path(Path::new("/"), &[PathBuf::from("/")], &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_system_trees() {
+        assert!(check_path(Path::new("/etc/x"), &[PathBuf::from("/etc")], &[]).is_err());
+        assert!(check_path(Path::new("/usr/local/data"), &[PathBuf::from("/usr")], &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_home_directories() {
+        assert!(check_path(Path::new("/home/u"), &[PathBuf::from("/home")], &[]).is_err());
+        assert!(check_path(Path::new("/root/data"), &[PathBuf::from("/root")], &[]).is_err());
+        let homes = passwd_homes("erigon:x:999:999::/var/lib/erigon:/usr/sbin/nologin\n");
+        let roots = [PathBuf::from("/var/lib")];
+        assert!(check_path(Path::new("/var/lib/erigon"), &roots, &homes).is_err());
+        assert!(check_path(Path::new("/var/lib"), &roots, &homes).is_err());
+        assert!(check_path(Path::new("/var/lib/beacon"), &roots, &homes).is_ok());
+    }
+
+    #[test]
+    fn reads_absolute_homes_from_passwd() {
+        let passwd = "# comment\n\
+                      root:x:0:0:root:/root:/bin/bash\n\
+                      sys:x:3:3:sys:/:/usr/sbin/nologin\n\
+                      nohome:x:4:4:::/bin/sh\n\
+                      erigon:x:999:999::/var/lib/erigon:/usr/sbin/nologin\n";
+        assert_eq!(
+            passwd_homes(passwd),
+            vec![PathBuf::from("/root"), PathBuf::from("/var/lib/erigon")]
+        );
+    }
+
+    #[test]
+    fn rejects_empty_relative_and_unnormalized_paths() {
+        let roots = [PathBuf::from("/data")];
+        assert!(check_path(Path::new(""), &roots, &[]).is_err());
+        assert!(check_path(Path::new("data/erigon"), &roots, &[]).is_err());
+        assert!(check_path(Path::new("/data/../etc"), &roots, &[]).is_err());
+    }
+
+    #[test]
+    fn rejects_root_left_by_trailing_separator() {
+        let root = temp_root("trailing");
+        // DELETE_DIRS="<root>/erigon;/" is split into the data dir and `/`
+        let value = format!("{};/", root.join("erigon").display());
+        let paths = value.split(';').map(PathBuf::from).collect::<Vec<_>>();
+        let err = check_removal_paths(&paths, std::slice::from_ref(&root))
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("\"/\""), "{}", err);
+        assert!(!err.contains("erigon"), "{}", err);
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn rejects_symlink_escaping_roots() {
+        let base = temp_root("symlink");
+        let root = base.join("root");
+        let outside = base.join("outside");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&outside).unwrap();
+        symlink(&outside, root.join("link")).unwrap();
+        let roots = [root.clone()];
+        assert!(check_path(&root.join("link"), &roots, &[]).is_err());
+        assert!(check_path(&root.join("link").join("chaindata"), &roots, &[]).is_err());
+        fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn accepts_missing_path_under_root() {
+        let root = temp_root("missing");
+        let path = root.join("erigon").join("chaindata");
+        assert!(check_path(&path, std::slice::from_ref(&root), &[]).is_ok());
+        assert!(check_removal_paths(&[path], std::slice::from_ref(&root)).is_ok());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn refuses_removal_without_roots() {
+        assert!(check_removal_paths(&[PathBuf::from("/data/erigon")], &[]).is_err());
+        assert!(check_removal_paths(&[], &[]).is_ok());
+    }
+}
@@ -0,0 +1,159 @@
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::run_command;
+
+const CHECK_INTERVAL: Duration = Duration::from_secs(2);
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum HealthProbe {
+    SystemdActive,
+    Tcp { address: String },
+    Http { url: String },
+    Command { command: Vec<String> },
+}
+
+fn default_health_timeout_secs() -> u64 {
+    120
+}
+
+/// Condition a restarted service has to meet within `timeout_secs`:
+///
+/// ```toml
+/// [[profiles.erigon.health_checks]]
+/// service = "erigon"
+/// type = "tcp"
+/// address = "127.0.0.1:8545"
+/// timeout_secs = 300
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HealthCheck {
+    pub service: String,
+    #[serde(default = "default_health_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(flatten)]
+    pub probe: HealthProbe,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HealthCheckResult {
+    pub service: String,
+    pub probe: HealthProbe,
+    pub passed: bool,
+    pub attempts: u32,
+    pub elapsed_secs: f64,
+    pub last_error: Option<String>,
+}
+
+/// Returned when a service did not become healthy, so the task can end in its own failure state
+#[derive(Debug, thiserror::Error)]
+#[error("Health checks failed: {0}")]
+pub struct HealthCheckError(pub String);
+
+impl HealthProbe {
+    fn probe(&self, service: &str) -> anyhow::Result<()> {
+        match self {
+            HealthProbe::SystemdActive => {
+                if systemctl::is_active(service)? {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("Unit {} is not active", service))
+                }
+            }
+            HealthProbe::Tcp { address } => {
+                let addr = address
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow::anyhow!("Cannot resolve {}", address))?;
+                TcpStream::connect_timeout(&addr, PROBE_TIMEOUT)?;
+                Ok(())
+            }
+            HealthProbe::Http { url } => {
+                let response = reqwest::blocking::Client::builder()
+                    .timeout(PROBE_TIMEOUT)
+                    .build()?
+                    .get(url)
+                    .send()?;
+                if response.status().is_success() {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!("{} returned {}", url, response.status()))
+                }
+            }
+            HealthProbe::Command { command } => {
+                let output = run_command(command, Some(PROBE_TIMEOUT))?;
+                if output.success() {
+                    Ok(())
+                } else {
+                    Err(anyhow::anyhow!(output.describe_failure()))
+                }
+            }
+        }
+    }
+}
+
+fn wait_until_healthy(check: &HealthCheck) -> HealthCheckResult {
+    let started = Instant::now();
+    let timeout = Duration::from_secs(check.timeout_secs);
+    let mut attempts = 0;
+    loop {
+        attempts += 1;
+        let last_error = match check.probe.probe(&check.service) {
+            Ok(()) => None,
+            Err(e) => Some(e.to_string()),
+        };
+        let passed = last_error.is_none();
+        if passed || started.elapsed() >= timeout {
+            return HealthCheckResult {
+                service: check.service.clone(),
+                probe: check.probe.clone(),
+                passed,
+                attempts,
+                elapsed_secs: started.elapsed().as_secs_f64(),
+                last_error,
+            };
+        }
+        thread::sleep(CHECK_INTERVAL);
+    }
+}
+
+/// Runs every check until it passes or times out, failing with `HealthCheckError` if any did not pass
+pub fn verify_services(
+    checks: &[HealthCheck],
+    results: &Arc<Mutex<Vec<HealthCheckResult>>>,
+) -> anyhow::Result<()> {
+    let mut failed = Vec::new();
+    for check in checks {
+        log::info!("Verifying service {}: {:?}", check.service, check.probe);
+        let result = wait_until_healthy(check);
+        if result.passed {
+            log::info!("Service {} is healthy", check.service);
+        } else {
+            log::error!(
+                "Service {} failed health check {:?}: {:?}",
+                check.service,
+                check.probe,
+                result.last_error
+            );
+            failed.push(format!(
+                "{} ({:?}): {}",
+                check.service,
+                check.probe,
+                result.last_error.clone().unwrap_or_default()
+            ));
+        }
+        results.lock().unwrap().push(result);
+    }
+    if failed.is_empty() {
+        Ok(())
+    } else {
+        Err(HealthCheckError(failed.join(", ")).into())
+    }
+}
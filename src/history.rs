@@ -0,0 +1,225 @@
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::default_state_dir;
+use crate::stage::{Stage, StageRecord};
+
+const HISTORY_FILE: &str = "history.jsonl";
+const DEFAULT_PAGE_SIZE: usize = 50;
+const MAX_PAGE_SIZE: usize = 500;
+
+/// Appends are read-modify-write because of the id, tasks finishing together must not interleave
+static HISTORY_LOCK: Mutex<()> = Mutex::new(());
+
+/// What started a task
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TaskTrigger {
+    /// `/start` or `/start/{profile}`
+    Api,
+    /// One of the configured `schedules`
+    Schedule,
+}
+
+/// Finished task, one JSON line in `<STATE_DIR>/history.jsonl`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryEntry {
+    pub id: u64,
+    pub profile: String,
+    pub trigger: TaskTrigger,
+    pub archive_url: String,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: DateTime<Utc>,
+    /// Terminal stage the task ended in
+    pub stage: Stage,
+    pub stages: Vec<StageRecord>,
+    /// Summed over all download attempts
+    pub bytes_downloaded: u64,
+    pub error_message: Option<String>,
+}
+
+/// Page of `GET /history`, newest entries first
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HistoryPage {
+    pub total: usize,
+    pub offset: usize,
+    pub limit: usize,
+    pub entries: Vec<HistoryEntry>,
+}
+
+/// Shared by all profiles, unlike the per profile `state_dir`
+pub fn history_path() -> PathBuf {
+    default_state_dir().join(HISTORY_FILE)
+}
+
+/// Lines that fail to parse are skipped, a truncated last line must not hide the rest
+fn read_history(path: &Path) -> anyhow::Result<Vec<HistoryEntry>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let contents = fs::read_to_string(path)
+        .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+    Ok(contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .filter_map(|line| match serde_json::from_str(line) {
+            Ok(entry) => Some(entry),
+            Err(e) => {
+                log::warn!("Skipping invalid line in {}: {}", path.display(), e);
+                None
+            }
+        })
+        .collect())
+}
+
+/// Assigns the next id to `entry` and appends it to the history file
+pub fn append_history(entry: HistoryEntry) -> anyhow::Result<HistoryEntry> {
+    append_entry(&history_path(), entry)
+}
+
+fn append_entry(path: &Path, mut entry: HistoryEntry) -> anyhow::Result<HistoryEntry> {
+    let _lock = HISTORY_LOCK.lock().unwrap();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)
+            .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    entry.id = read_history(path)?
+        .iter()
+        .map(|entry| entry.id)
+        .max()
+        .unwrap_or(0)
+        + 1;
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    // a line truncated by a crash would swallow the new entry
+    let truncated = fs::read(path)
+        .map(|contents| !contents.is_empty() && !contents.ends_with(b"\n"))
+        .unwrap_or(false);
+    if truncated {
+        line.insert(0, '\n');
+    }
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))?;
+    Ok(entry)
+}
+
+pub fn history_page(offset: usize, limit: Option<usize>) -> anyhow::Result<HistoryPage> {
+    read_page(&history_path(), offset, limit)
+}
+
+fn read_page(path: &Path, offset: usize, limit: Option<usize>) -> anyhow::Result<HistoryPage> {
+    let limit = limit.unwrap_or(DEFAULT_PAGE_SIZE).min(MAX_PAGE_SIZE);
+    let entries = read_history(path)?;
+    Ok(HistoryPage {
+        total: entries.len(),
+        offset,
+        limit,
+        entries: entries.into_iter().rev().skip(offset).take(limit).collect(),
+    })
+}
+
+pub fn find_history_entry(id: u64) -> anyhow::Result<Option<HistoryEntry>> {
+    Ok(read_history(&history_path())?
+        .into_iter()
+        .find(|entry| entry.id == id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_history(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "pipe_updater_history_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(HISTORY_FILE)
+    }
+
+    fn entry(profile: &str) -> HistoryEntry {
+        HistoryEntry {
+            id: 0,
+            profile: profile.into(),
+            trigger: TaskTrigger::Api,
+            archive_url: format!("http://localhost/{}.tar.lz4", profile),
+            started_at: Utc::now(),
+            finished_at: Utc::now(),
+            stage: Stage::Finished,
+            stages: vec![],
+            bytes_downloaded: 0,
+            error_message: None,
+        }
+    }
+
+    fn append(path: &Path, count: usize) {
+        for idx in 0..count {
+            append_entry(path, entry(&format!("profile{}", idx))).unwrap();
+        }
+    }
+
+    #[test]
+    fn assigns_increasing_ids() {
+        let path = temp_history("ids");
+        let first = append_entry(&path, entry("erigon")).unwrap();
+        let second = append_entry(&path, entry("beacon")).unwrap();
+        let entries = read_history(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!((first.id, second.id), (1, 2));
+        assert_eq!(entries.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn skips_invalid_lines() {
+        let path = temp_history("invalid");
+        append_entry(&path, entry("erigon")).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .and_then(|mut file| file.write_all(b"\n{\"id\": 7, trunc"))
+            .unwrap();
+        let before = read_history(&path).unwrap();
+        // the truncated line is not counted when the next id is assigned
+        let next = append_entry(&path, entry("beacon")).unwrap();
+        let after = read_history(&path).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(before.len(), 1);
+        assert_eq!(next.id, 2);
+        assert_eq!(after.iter().map(|e| e.id).collect::<Vec<_>>(), vec![1, 2]);
+    }
+
+    #[test]
+    fn pages_newest_first() {
+        let path = temp_history("pages");
+        append(&path, 5);
+        let page = read_page(&path, 1, Some(2)).unwrap();
+        let empty = read_page(&path, 10, None).unwrap();
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+        assert_eq!(page.total, 5);
+        assert_eq!(
+            page.entries.iter().map(|e| e.id).collect::<Vec<_>>(),
+            vec![4, 3]
+        );
+        assert!(empty.entries.is_empty());
+        assert_eq!(empty.limit, DEFAULT_PAGE_SIZE);
+    }
+
+    #[test]
+    fn caps_page_size() {
+        let page = read_page(&temp_history("missing"), 0, Some(10_000)).unwrap();
+        assert_eq!(page.limit, MAX_PAGE_SIZE);
+        assert_eq!(page.total, 0);
+    }
+}
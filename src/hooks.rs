@@ -0,0 +1,144 @@
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::command::{run_command, CommandOutput};
+use crate::stage::Stage;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookWhen {
+    Pre,
+    Post,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HookFailurePolicy {
+    Abort,
+    Continue,
+}
+
+fn default_hook_timeout_secs() -> u64 {
+    300
+}
+
+fn default_failure_policy() -> HookFailurePolicy {
+    HookFailurePolicy::Abort
+}
+
+/// Command run before or after a stage:
+///
+/// ```toml
+/// [[profiles.erigon.hooks]]
+/// stage = "removing_old_files"
+/// when = "pre"
+/// command = ["/usr/local/bin/export-node-keys", "/backup"]
+/// timeout_secs = 60
+/// on_failure = "abort"
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Hook {
+    pub stage: Stage,
+    pub when: HookWhen,
+    pub command: Vec<String>,
+    #[serde(default = "default_hook_timeout_secs")]
+    pub timeout_secs: u64,
+    #[serde(default = "default_failure_policy")]
+    pub on_failure: HookFailurePolicy,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct HookResult {
+    pub stage: Stage,
+    pub when: HookWhen,
+    pub started_at: DateTime<Utc>,
+    pub success: bool,
+    pub error_message: Option<String>,
+    pub output: Option<CommandOutput>,
+}
+
+/// Hooks run around the pipeline stages only, recovery and terminal stages have none
+pub fn validate_hooks(hooks: &[Hook]) -> anyhow::Result<()> {
+    for hook in hooks {
+        if !hook.stage.is_pipeline() {
+            return Err(anyhow::anyhow!(
+                "Hooks cannot run for stage {}, only for stages entered by pipeline steps, verifying_signature and verifying",
+                hook.stage
+            ));
+        }
+        if hook.command.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Hook for {} ({:?}) has empty command",
+                hook.stage,
+                hook.when
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Runs every hook registered for `stage` and `when`, in declaration order.
+/// Fails on the first failing hook with the `abort` policy, the error includes the captured output.
+pub fn run_hooks(
+    hooks: &[Hook],
+    stage: Stage,
+    when: HookWhen,
+    results: &Arc<Mutex<Vec<HookResult>>>,
+) -> anyhow::Result<()> {
+    for hook in hooks
+        .iter()
+        .filter(|hook| hook.stage == stage && hook.when == when)
+    {
+        log::info!("Running {:?} hook for {}: {:?}", when, stage, hook.command);
+        let started_at = Utc::now();
+        let (output, error_message) =
+            match run_command(&hook.command, Some(Duration::from_secs(hook.timeout_secs))) {
+                Ok(output) if output.success() => (Some(output), None),
+                Ok(output) => {
+                    let error_message = format!(
+                        "{}\nstdout: {}\nstderr: {}",
+                        output.describe_failure(),
+                        output.stdout.trim(),
+                        output.stderr.trim()
+                    );
+                    (Some(output), Some(error_message))
+                }
+                Err(e) => (None, Some(e.to_string())),
+            };
+        results.lock().unwrap().push(HookResult {
+            stage,
+            when,
+            started_at,
+            success: error_message.is_none(),
+            error_message: error_message.clone(),
+            output,
+        });
+        if let Some(error_message) = error_message {
+            match hook.on_failure {
+                HookFailurePolicy::Abort => {
+                    log::error!("{:?} hook for {} failed: {}", when, stage, error_message);
+                    return Err(anyhow::anyhow!(
+                        "{:?} hook for {} failed: {}",
+                        when,
+                        stage,
+                        error_message
+                    ));
+                }
+                HookFailurePolicy::Continue => {
+                    log::warn!(
+                        "{:?} hook for {} failed, continuing: {}",
+                        when,
+                        stage,
+                        error_message
+                    );
+                }
+            }
+        }
+    }
+    Ok(())
+}
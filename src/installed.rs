@@ -0,0 +1,201 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::resolve_expected_checksum;
+use crate::config::Profile;
+use crate::remote::{fetch_archive_info, ArchiveInfo};
+
+/// What the last finished update of a profile installed, kept in `<state_dir>/<profile>.json`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct InstalledVersion {
+    pub profile: String,
+    pub archive_url: String,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+    pub size: Option<u64>,
+    /// `algorithm:hex` of the archive when it was verified
+    pub checksum: Option<String>,
+    pub completed_at: DateTime<Utc>,
+}
+
+pub fn installed_version_path(profile: &Profile) -> PathBuf {
+    profile.state_dir.join(format!("{}.json", profile.name))
+}
+
+impl InstalledVersion {
+    pub fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        serde_json::from_str(&contents)
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))
+    }
+
+    /// Written to a temporary file first, so a crash never leaves a truncated record
+    pub fn save(&self, path: &Path) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)
+                .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", parent.display(), e))?;
+        }
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, path)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    /// Whether `remote` is the archive that was installed. Without an ETag the
+    /// Last-Modified header and size have to match, without either nothing is assumed.
+    pub fn matches(&self, remote: &ArchiveInfo, checksum: Option<&str>) -> bool {
+        if self.archive_url != remote.url {
+            return false;
+        }
+        if let (Some(installed), Some(expected)) = (&self.checksum, checksum) {
+            return installed == expected;
+        }
+        match (&self.etag, &remote.etag) {
+            (Some(installed), Some(remote)) => installed == remote,
+            _ => match (&self.last_modified, &remote.last_modified) {
+                (Some(installed), Some(remote_modified)) => {
+                    installed == remote_modified && self.size == remote.size
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
+/// Comparison of the remote archive with the installed version, returned by `GET /check`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdateCheck {
+    pub profile: String,
+    pub installed: Option<InstalledVersion>,
+    pub remote: ArchiveInfo,
+    pub update_available: bool,
+    /// Seconds since the remote archive was last modified
+    pub age_secs: Option<i64>,
+}
+
+/// Blocking, only reads the installed version record and queries the archive server
+pub fn check_for_update(profile: &Profile) -> anyhow::Result<UpdateCheck> {
+    let installed = InstalledVersion::load(&installed_version_path(profile))?;
+    let remote = fetch_archive_info(&profile.archive_url)?;
+    let update_available = match &installed {
+        Some(installed) => {
+            let checksum = resolve_expected_checksum(
+                &profile.archive_url,
+                &profile.checksum,
+                profile.checksum_sidecar,
+            )?
+            .map(|c| format!("{}:{}", c.algorithm, c.hex));
+            !installed.matches(&remote, checksum.as_deref())
+        }
+        None => true,
+    };
+    let age_secs = remote
+        .last_modified
+        .as_deref()
+        .and_then(|modified| DateTime::parse_from_rfc2822(modified).ok())
+        .map(|modified| (Utc::now() - modified.with_timezone(&Utc)).num_seconds());
+    Ok(UpdateCheck {
+        profile: profile.name.clone(),
+        installed,
+        remote,
+        update_available,
+        age_secs,
+    })
+}
+
+/// Blocking, returns the installed version if the remote archive is the same one
+pub fn installed_if_current(profile: &Profile) -> anyhow::Result<Option<InstalledVersion>> {
+    let check = check_for_update(profile)?;
+    if check.update_available {
+        Ok(None)
+    } else {
+        Ok(check.installed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URL: &str = "http://localhost/erigon.tar.lz4";
+
+    fn installed() -> InstalledVersion {
+        InstalledVersion {
+            profile: "erigon".into(),
+            archive_url: URL.into(),
+            etag: Some("\"v1\"".into()),
+            last_modified: Some("Sun, 01 Mar 2026 03:00:00 GMT".into()),
+            size: Some(100),
+            checksum: None,
+            completed_at: Utc::now(),
+        }
+    }
+
+    fn remote() -> ArchiveInfo {
+        ArchiveInfo {
+            url: URL.into(),
+            size: Some(100),
+            etag: Some("\"v1\"".into()),
+            last_modified: Some("Sun, 01 Mar 2026 03:00:00 GMT".into()),
+        }
+    }
+
+    #[test]
+    fn compares_etag_first() {
+        assert!(installed().matches(&remote(), None));
+        let changed = ArchiveInfo {
+            etag: Some("\"v2\"".into()),
+            ..remote()
+        };
+        assert!(!installed().matches(&changed, None));
+    }
+
+    #[test]
+    fn never_matches_another_url() {
+        let other = ArchiveInfo {
+            url: "http://localhost/beacon.tar.lz4".into(),
+            ..remote()
+        };
+        assert!(!installed().matches(&other, None));
+    }
+
+    #[test]
+    fn checksum_decides_when_both_are_known() {
+        let verified = InstalledVersion {
+            checksum: Some("sha256:aa".into()),
+            ..installed()
+        };
+        assert!(verified.matches(&remote(), Some("sha256:aa")));
+        assert!(!verified.matches(&remote(), Some("sha256:bb")));
+    }
+
+    #[test]
+    fn falls_back_to_last_modified_and_size() {
+        let without_etag = ArchiveInfo {
+            etag: None,
+            ..remote()
+        };
+        assert!(installed().matches(&without_etag, None));
+        let resized = ArchiveInfo {
+            size: Some(200),
+            ..without_etag.clone()
+        };
+        assert!(!installed().matches(&resized, None));
+        let unknown = ArchiveInfo {
+            last_modified: None,
+            ..without_etag
+        };
+        assert!(!installed().matches(&unknown, None));
+    }
+}
@@ -1,239 +1,65 @@
+mod backup;
+mod checksum;
+mod command;
+mod config;
+mod download;
+mod guard;
+mod health;
+mod history;
+mod hooks;
+mod installed;
+mod overlap;
+mod ownership;
+mod pipeline;
+mod plan;
+mod preflight;
+mod remote;
+mod removal;
+mod scheduler;
+mod services;
+mod signature;
+mod stage;
+mod stream;
+mod task_state;
+mod update_task;
+
+use std::collections::BTreeMap;
+use std::env;
 use std::path::PathBuf;
-use std::{env, fs, thread};
 
 use actix_web::{get, post, web, App, HttpResponse, HttpServer, Responder};
+use chrono::Utc;
+use serde::Deserialize;
+
+use crate::config::{default_state_dir, Config, Profile, StartOverrides};
+use crate::history::{find_history_entry, history_page, TaskTrigger};
+use crate::installed::{check_for_update, installed_if_current, InstalledVersion};
+use crate::overlap::check_overlaps;
+use crate::pipeline::Step;
+use crate::plan::build_plan;
+use crate::preflight::preflight;
+use crate::scheduler::{ScheduleConfig, ScheduleState};
+use crate::task_state::find_interrupted_tasks;
+use crate::update_task::UpdateTask;
 
 use lazy_static::lazy_static; // 1.4.0
-use pipe_downloader::pipe_downloader::{PipeDownloader, PipeDownloaderOptions};
-use serde_json::json;
 use std::sync::Arc;
 use std::sync::Mutex;
-use std::time::Duration;
 use structopt::StructOpt;
 use tokio::task;
 
-struct UpdateTask {
-    downloader: Arc<Mutex<PipeDownloader>>,
-    services_to_stop: Vec<String>,
-    is_running: Arc<Mutex<bool>>,
-    stage: Arc<Mutex<String>>,
-    error_message: Arc<Mutex<Option<String>>>,
-    paths_to_remove: Vec<PathBuf>,
-    target_user: Option<String>,
-    target_group: Option<String>,
-    target_paths: Vec<PathBuf>,
-}
-
-fn update_task_main(
-    services_to_stop: Vec<String>,
-    downloader: Arc<Mutex<PipeDownloader>>,
-    paths_to_remove: Vec<PathBuf>,
-    target_user: Option<String>,
-    target_group: Option<String>,
-    target_paths: Vec<PathBuf>,
-    stage: Arc<Mutex<String>>,
-) -> anyhow::Result<()> {
-    *stage.lock().unwrap() = "stopping_services".to_string();
-    for service_to_stop in &services_to_stop {
-        log::info!("Stopping process: {}", service_to_stop);
-        match systemctl::stop(service_to_stop) {
-            Ok(_) => {
-                log::info!("Process stopped: {}", service_to_stop);
-            }
-            Err(e) => {
-                log::error!("Failed to stop process {}: {}", service_to_stop, e);
-                return Err(anyhow::anyhow!(
-                    "Failed to stop process {}: {}",
-                    service_to_stop,
-                    e
-                ));
-            }
-        };
-    }
-    *stage.lock().unwrap() = "removing_old_files".to_string();
-    for path in paths_to_remove {
-        if path.is_dir() {
-            log::info!("Removing directory: {}", path.display());
-            if let Err(err) = fs::remove_dir_all(&path) {
-                log::error!("Error removing directory: {}", err);
-                return Err(anyhow::anyhow!("Error removing directory: {}", err));
-            }
-        } else if path.is_file() {
-            log::info!("Removing file: {}", path.display());
-            if let Err(err) = fs::remove_file(&path) {
-                log::error!("Error removing file: {}", err);
-                return Err(anyhow::anyhow!("Error removing file: {}", err));
-            }
-        } else {
-            log::info!("Trying to remove, path not exists: {}", path.display());
-        }
-    }
-
-    //let system see that the directories are removed
-    thread::sleep(Duration::from_secs(1));
-
-    log::info!("Start download");
-    *stage.lock().unwrap() = "downloading".to_string();
-
-    match downloader.lock().unwrap().start_download() {
-        Ok(_) => {}
-        Err(e) => {
-            log::error!("Error started downloading: {}", e);
-            return Err(anyhow::anyhow!("Error started downloading: {}", e));
-        }
-    };
-
-    while !downloader.lock().unwrap().is_finished() {
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
-
-    log::info!("Download finished");
-
-    if let Some(error_message) = downloader.lock().unwrap().get_progress().error_message {
-        log::error!("Error downloading: {}", error_message);
-        return Err(anyhow::anyhow!(
-            "Download failed with error: {}",
-            error_message
-        ));
-    }
-
-    *stage.lock().unwrap() = "changing_ownership".to_string();
-
-    if let (Some(target_user), Some(target_group)) = (target_user, target_group) {
-        for target_path in target_paths {
-            log::info!(
-                "Changing path ownership: {} to {}:{}",
-                target_path.display(),
-                target_user,
-                target_group
-            );
-            let command = std::format!(
-                "chown -R {}:{} {}",
-                target_user,
-                target_group,
-                target_path.display()
-            )
-            .to_string();
-            match std::process::Command::new("/bin/bash")
-                .arg("-c")
-                .arg(command)
-                .output()
-            {
-                Ok(_) => {}
-                Err(e) => {
-                    println!("Error changing owner: {}", e);
-                    return Err(anyhow::anyhow!("Error changing owner: {}", e));
-                }
-            };
-        }
-    }
-
-    *stage.lock().unwrap() = "starting_service".to_string();
-
-    for service_to_stop in &services_to_stop {
-        log::info!("Starting service: {}", service_to_stop);
-        match systemctl::restart(service_to_stop) {
-            Ok(_) => {}
-            Err(e) => {
-                log::error!("Error restarting service: {}", e);
-                return Err(anyhow::anyhow!("Error starting service: {}", e));
-            }
-        };
-    }
-    *stage.lock().unwrap() = "finished".to_string();
-    Ok(())
-}
-
-impl UpdateTask {
-    fn new(
-        downloader: PipeDownloader,
-        services_to_stop: Vec<String>,
-        paths_to_remove: Vec<PathBuf>,
-        target_user: Option<String>,
-        target_group: Option<String>,
-        target_paths: Vec<PathBuf>,
-    ) -> Self {
-        Self {
-            downloader: Arc::new(Mutex::new(downloader)),
-            services_to_stop,
-            is_running: Arc::new(Mutex::new(false)),
-            paths_to_remove,
-            target_user,
-            target_group,
-            target_paths,
-            error_message: Arc::new(Mutex::new(None)),
-            stage: Arc::new(Mutex::new("init".to_string())),
-        }
-    }
-
-    fn pause(self: &Self) {
-        self.downloader.lock().unwrap().pause_download();
-    }
-
-    fn resume(self: &Self) {
-        self.downloader.lock().unwrap().resume_download();
-    }
-
-    fn abort(self: &Self) {
-        self.downloader.lock().unwrap().signal_stop();
-    }
-
-    fn is_running(self: &Self) -> bool {
-        *self.is_running.lock().unwrap()
-    }
-
-    fn get_progress(self: &Self) -> serde_json::Value {
-        json!({
-            "stage": self.stage.lock().unwrap().clone(),
-            "errorMessage": self.error_message.lock().unwrap().clone(),
-            "downloadProgress": self.downloader.lock().unwrap().get_progress_json()
-        })
-    }
-
-    fn run(self: &mut Self) -> anyhow::Result<()> {
-        if *self.is_running.lock().unwrap() {
-            return Err(anyhow::anyhow!("Task is already running"));
-        }
-        *self.is_running.lock().unwrap() = true;
-        let services_to_stop = self.services_to_stop.clone();
-        let downloader = self.downloader.clone();
-        let is_running = self.is_running.clone();
-        let paths_to_remove = self.paths_to_remove.clone();
-        let target_user = self.target_user.clone();
-        let target_group = self.target_group.clone();
-        let target_paths = self.target_paths.clone();
-        let error_message = self.error_message.clone();
-        let stage = self.stage.clone();
-
-        thread::spawn(move || {
-            match update_task_main(
-                services_to_stop,
-                downloader,
-                paths_to_remove,
-                target_user,
-                target_group,
-                target_paths,
-                stage,
-            ) {
-                Ok(_) => {}
-                Err(e) => {
-                    *error_message.lock().unwrap() = Some(e.to_string());
-                    println!("Error running update task: {}", e);
-                }
-            };
-            *is_running.lock().unwrap() = false;
-        });
-        Ok(())
-    }
-}
-
 struct AppState {
-    updater: Option<UpdateTask>,
+    /// Keyed by profile name, a finished task stays until its profile is started again
+    tasks: BTreeMap<String, UpdateTask>,
+    config: Config,
+    schedules: Vec<ScheduleState>,
 }
 
 lazy_static! {
     static ref UPDATER_STATE: Arc<Mutex<AppState>> = Arc::new(Mutex::new(AppState {
-        updater: None
+        tasks: BTreeMap::new(),
+        config: Config::default(),
+        schedules: Vec::new(),
     }));
 }
 
@@ -250,6 +76,18 @@ struct Cli {
     /// Listen port
     #[structopt(long, default_value = "15100")]
     pub listen_port: u16,
+
+    /// TOML file with named update profiles
+    #[structopt(long)]
+    pub config: Option<PathBuf>,
+
+    /// Print what an update would do and exit, without starting the server
+    #[structopt(long)]
+    pub plan: bool,
+
+    /// Profile used by --plan, environment variables are used when not given
+    #[structopt(long)]
+    pub profile: Option<String>,
 }
 
 #[get("/hello/{name}")]
@@ -260,142 +98,331 @@ async fn greet(name: web::Path<String>) -> impl Responder {
 #[get("/progress")]
 async fn progress_endpoint() -> impl Responder {
     let updater_state = UPDATER_STATE.lock().unwrap();
-    if let Some(progress) = updater_state
-        .updater
-        .as_ref()
-        .map(|upd| Some(upd.get_progress()))
-        .unwrap_or(None)
-    {
-        return web::Json(progress);
+    let tasks = updater_state
+        .tasks
+        .iter()
+        .map(|(task_id, task)| (task_id.clone(), task.get_progress()))
+        .collect::<serde_json::Map<_, _>>();
+    // with zero or one task the top level keeps the single task shape dashboards read
+    let mut progress = match tasks.len() {
+        0 => serde_json::json!({
+            "downloadProgress": serde_json::Value::Null,
+            "stage": "no_task",
+            "error_message": serde_json::Value::Null,
+        }),
+        1 => tasks.values().next().cloned().unwrap_or_default(),
+        _ => serde_json::json!({}),
     };
-    return web::Json(
-        serde_json::json!({"downloadProgress": serde_json::Value::Null, "stage": "no_task", "error_message": serde_json::Value::Null}),
-    );
+    progress["tasks"] = serde_json::Value::Object(tasks);
+    progress["schedules"] = serde_json::json!(updater_state.schedules);
+    web::Json(progress)
 }
 
-async fn update() -> String {
+#[get("/progress/{task}")]
+async fn progress_task_endpoint(task_id: web::Path<String>) -> impl Responder {
+    match UPDATER_STATE.lock().unwrap().tasks.get(task_id.as_str()) {
+        Some(task) => HttpResponse::Ok().json(task.get_progress()),
+        None => HttpResponse::NotFound()
+            .json(serde_json::json!({ "error": format!("No task {}", task_id) })),
+    }
+}
+
+async fn update(profile: Profile, trigger: TaskTrigger) -> String {
+    let mut updater_state = UPDATER_STATE.lock().unwrap();
+    if updater_state
+        .tasks
+        .get(&profile.name)
+        .map(|task| task.is_running())
+        .unwrap_or(false)
     {
-        let mut updater_state = UPDATER_STATE.lock().unwrap();
-        if updater_state
-            .updater
-            .as_ref()
-            .map(|upd| upd.is_running())
-            .unwrap_or(false)
-        {
-            return format!("Already running");
-        } else {
-            let output_dir =
-                PathBuf::from(env::var("OUTPUT_DIR").unwrap_or_else(|_| "output".into()));
-            let delete_dirs = env::var("DELETE_DIRS")
-                .map(|s| {
-                    s.split(";")
-                        .map(|spl| PathBuf::from(spl))
-                        .collect::<Vec<_>>()
-                })
-                .unwrap_or_else(|_| vec![]);
-
-            let services_to_stop = env::var("SERVICES_TO_STOP")
-                .map(|s| s.split(";").map(|spl| spl.to_string()).collect::<Vec<_>>())
-                .unwrap_or_else(|_| vec![]);
-            println!("Services to stop: {:?}", services_to_stop);
-            let target_user = env::var("TARGET_USER").unwrap_or_else(|_| "erigon".into());
-            let target_group = env::var("TARGET_GROUP").unwrap_or_else(|_| "erigon".into());
-            let target_change_owner_paths = env::var("CHANGE_OWNER_PATHS")
-                .map(|s| {
-                    s.split(";")
-                        .map(|spl| PathBuf::from(spl))
-                        .collect::<Vec<_>>()
-                })
-                .unwrap_or_else(|_| vec![]);
-
-            let url = env::var("ARCHIVE_URL")
-                .unwrap_or_else(|_| "http://mumbai-main.golem.network:14372/beacon.tar.lz4".into());
-
-            let pd = PipeDownloader::new(&url, &output_dir, PipeDownloaderOptions::from_env());
-
-            let mut updater = UpdateTask::new(
-                pd,
-                services_to_stop,
-                delete_dirs,
-                Some(target_user),
-                Some(target_group),
-                target_change_owner_paths,
-            );
-            if let Err(e) = updater.run() {
-                println!("Error starting update task: {}", e);
-                return format!("Error starting update task: {}", e);
-            };
-            updater_state.updater = Some(updater);
+        return format!("Task {} is already running", profile.name);
+    }
+    let running = updater_state
+        .tasks
+        .values()
+        .filter(|task| task.is_running())
+        .map(|task| task.profile());
+    if let Err(e) = check_overlaps(&profile, running) {
+        return format!("Update refused: {}", e);
+    }
+    println!("Services to stop: {:?}", profile.services_to_stop);
+
+    let task_id = profile.name.clone();
+    let mut updater = UpdateTask::new(profile, trigger);
+    if let Err(e) = updater.run() {
+        println!("Error starting update task: {}", e);
+        return format!("Error starting update task: {}", e);
+    };
+    updater_state.tasks.insert(task_id.clone(), updater);
+    format!("Update {} started!", task_id)
+}
+
+/// Applies the optional JSON body of a start request to the selected profile
+fn resolve_profile(profile_name: Option<&str>, body: &[u8]) -> anyhow::Result<Profile> {
+    let updater_state = UPDATER_STATE.lock().unwrap();
+    let profile = match profile_name {
+        Some(profile_name) => updater_state.config.profile(profile_name)?,
+        None => Profile::from_env(),
+    };
+    if body.iter().all(u8::is_ascii_whitespace) {
+        return Ok(profile);
+    }
+    let overrides: StartOverrides =
+        serde_json::from_slice(body).map_err(|e| anyhow::anyhow!("Invalid request body: {}", e))?;
+    log::info!("Applying start overrides: {:?}", overrides);
+    overrides.apply(profile, &updater_state.config.overrides)
+}
+
+/// Query of the start endpoints, `force` starts the update even if the archive is already installed
+#[derive(Debug, Default, Deserialize)]
+struct StartQuery {
+    #[serde(default)]
+    force: bool,
+}
+
+/// Blocking checks run before a task is created
+fn check_start(profile: &Profile, force: bool) -> anyhow::Result<Option<InstalledVersion>> {
+    if !force && profile.steps.contains(&Step::Download) {
+        match installed_if_current(profile) {
+            Ok(Some(installed)) => return Ok(Some(installed)),
+            Ok(None) => {}
+            Err(e) => log::warn!("Cannot compare with installed version: {}", e),
         }
     }
+    preflight(profile)?;
+    Ok(None)
+}
 
-    format!("Update started!")
+async fn start(
+    profile_name: Option<&str>,
+    body: &[u8],
+    force: bool,
+    trigger: TaskTrigger,
+) -> String {
+    let profile = match resolve_profile(profile_name, body) {
+        Ok(profile) => profile,
+        Err(e) => return format!("Error starting update task: {}", e),
+    };
+    let profile = match web::block(move || check_start(&profile, force).map(|c| (profile, c))).await
+    {
+        Ok(Ok((_, Some(installed)))) => {
+            return format!(
+                "Already up to date: {} installed at {}",
+                installed.archive_url, installed.completed_at
+            )
+        }
+        Ok(Ok((profile, None))) => profile,
+        Ok(Err(e)) => return format!("Update refused: {}", e),
+        Err(e) => return format!("Error starting update task: {}", e),
+    };
+    update(profile, trigger).await
 }
+
 #[post("/start")]
-async fn start_update() -> impl Responder {
-    update().await
+async fn start_update(body: web::Bytes, query: web::Query<StartQuery>) -> impl Responder {
+    start(None, &body, query.force, TaskTrigger::Api).await
+}
+
+#[post("/start/{profile}")]
+async fn start_update_profile(
+    profile_name: web::Path<String>,
+    body: web::Bytes,
+    query: web::Query<StartQuery>,
+) -> impl Responder {
+    start(Some(&profile_name), &body, query.force, TaskTrigger::Api).await
+}
+
+async fn plan(profile_name: Option<&str>, body: &[u8]) -> HttpResponse {
+    let profile = match resolve_profile(profile_name, body) {
+        Ok(profile) => profile,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    };
+    match web::block(move || build_plan(&profile)).await {
+        Ok(plan) => HttpResponse::Ok().json(plan),
+        Err(e) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+#[post("/plan")]
+async fn plan_update(body: web::Bytes) -> impl Responder {
+    plan(None, &body).await
+}
+
+#[post("/plan/{profile}")]
+async fn plan_update_profile(profile_name: web::Path<String>, body: web::Bytes) -> impl Responder {
+    plan(Some(&profile_name), &body).await
+}
+
+/// Compares the remote archive with the installed version without creating a task
+async fn check(profile_name: Option<&str>) -> HttpResponse {
+    let profile = match resolve_profile(profile_name, &[]) {
+        Ok(profile) => profile,
+        Err(e) => {
+            return HttpResponse::BadRequest().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    };
+    match web::block(move || check_for_update(&profile)).await {
+        Ok(Ok(check)) => HttpResponse::Ok().json(check),
+        Ok(Err(e)) => {
+            HttpResponse::BadGateway().json(serde_json::json!({ "error": e.to_string() }))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+#[get("/check")]
+async fn check_update() -> impl Responder {
+    check(None).await
+}
+
+#[get("/check/{profile}")]
+async fn check_update_profile(profile_name: web::Path<String>) -> impl Responder {
+    check(Some(&profile_name)).await
+}
+
+/// Query of `GET /history`, newest entries come first
+#[derive(Debug, Default, Deserialize)]
+struct HistoryQuery {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[get("/history")]
+async fn history_endpoint(query: web::Query<HistoryQuery>) -> impl Responder {
+    let HistoryQuery { offset, limit } = query.into_inner();
+    match web::block(move || history_page(offset, limit)).await {
+        Ok(Ok(page)) => HttpResponse::Ok().json(page),
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    }
+}
+
+#[get("/history/{id}")]
+async fn history_entry_endpoint(id: web::Path<u64>) -> impl Responder {
+    let id = id.into_inner();
+    match web::block(move || find_history_entry(id)).await {
+        Ok(Ok(Some(entry))) => HttpResponse::Ok().json(entry),
+        Ok(Ok(None)) => HttpResponse::NotFound()
+            .json(serde_json::json!({ "error": format!("No task {} in history", id) })),
+        Ok(Err(e)) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+        Err(e) => {
+            HttpResponse::InternalServerError().json(serde_json::json!({ "error": e.to_string() }))
+        }
+    }
 }
 
 #[get("/debug_start")]
 async fn start_update_debug() -> impl Responder {
-    update().await
+    start(None, &[], false, TaskTrigger::Api).await
+}
+
+/// Runs `action` on the task `task_id`, or on the only running task when no id is given
+fn with_running_task(
+    task_id: Option<&str>,
+    action: impl FnOnce(&UpdateTask),
+) -> anyhow::Result<String> {
+    let updater_state = UPDATER_STATE.lock().unwrap();
+    let (task_id, task) = match task_id {
+        Some(task_id) => updater_state
+            .tasks
+            .get_key_value(task_id)
+            .ok_or_else(|| anyhow::anyhow!("No task {}", task_id))?,
+        None => {
+            let mut running = updater_state
+                .tasks
+                .iter()
+                .filter(|(_, task)| task.is_running());
+            match (running.next(), running.next()) {
+                (Some(task), None) => task,
+                (None, _) => return Err(anyhow::anyhow!("No task is running")),
+                (Some(_), Some(_)) => {
+                    return Err(anyhow::anyhow!("Several tasks are running, give a task id"))
+                }
+            }
+        }
+    };
+    if !task.is_running() {
+        return Err(anyhow::anyhow!("Task {} is not running", task_id));
+    }
+    action(task);
+    Ok(task_id.clone())
+}
+
+fn pause(task_id: Option<&str>) -> String {
+    match with_running_task(task_id, UpdateTask::pause) {
+        Ok(task_id) => format!("Update {} paused", task_id),
+        Err(e) => format!("Failed to pause: {}", e),
+    }
+}
+
+fn resume(task_id: Option<&str>) -> String {
+    match with_running_task(task_id, UpdateTask::resume) {
+        Ok(task_id) => format!("Update {} resumed", task_id),
+        Err(e) => format!("Failed to resume: {}", e),
+    }
 }
 
+fn abort(task_id: Option<&str>) -> String {
+    match with_running_task(task_id, UpdateTask::abort) {
+        Ok(task_id) => format!("Update {} aborted", task_id),
+        Err(e) => format!("Failed to abort: {}", e),
+    }
+}
 
 #[get("/pause")]
 async fn pause_update() -> impl Responder {
-    if let Some(updater) = UPDATER_STATE.lock().unwrap().updater.as_ref() {
-        if updater.is_running() {
-            updater.pause();
-            return format!("Update paused");
-        }
-    }
-    format!("Failed to pause!")
+    pause(None)
+}
+
+#[get("/pause/{task}")]
+async fn pause_update_task(task_id: web::Path<String>) -> impl Responder {
+    pause(Some(&task_id))
 }
 
 #[get("/resume")]
 async fn resume_update() -> impl Responder {
-    if let Some(updater) = UPDATER_STATE.lock().unwrap().updater.as_ref() {
-        if updater.is_running() {
-            updater.resume();
-            return format!("Update resumed");
-        }
-    }
-    format!("Failed to resume!")
+    resume(None)
+}
+
+#[get("/resume/{task}")]
+async fn resume_update_task(task_id: web::Path<String>) -> impl Responder {
+    resume(Some(&task_id))
 }
 
 #[get("/abort")]
 async fn abort_update() -> impl Responder {
-    if let Some(updater) = UPDATER_STATE.lock().unwrap().updater.as_ref() {
-        if updater.is_running() {
-            updater.abort();
-            return format!("Update aborted");
-        }
-    }
-    format!("Failed to abort!")
+    abort(None)
 }
 
+#[get("/abort/{task}")]
+async fn abort_update_task(task_id: web::Path<String>) -> impl Responder {
+    abort(Some(&task_id))
+}
 
 // for debug only, it can be disabled in production
 async fn update_loop() -> anyhow::Result<()> {
     loop {
-        let is_running = UPDATER_STATE
+        for task in UPDATER_STATE
             .lock()
             .unwrap()
-            .updater
-            .as_ref()
-            .map(|pd| pd.is_running())
-            .unwrap_or(false);
-        if is_running {
-            if let Some(progress_human_line) = UPDATER_STATE
-                .lock()
-                .unwrap()
-                .updater
-                .as_ref()
-                .map(|pd| pd.downloader.lock().unwrap().get_progress_human_line())
-            {
-                log::debug!("{}", progress_human_line);
-            }
+            .tasks
+            .values()
+            .filter(|task| task.is_running())
+        {
+            log::debug!("{}", task.get_progress_human_line());
         }
 
         /*{
@@ -407,6 +434,67 @@ async fn update_loop() -> anyhow::Result<()> {
     }
 }
 
+/// Starts scheduled updates through the same path as `/start/{profile}`
+async fn scheduler_loop(schedules: Vec<ScheduleConfig>) -> anyhow::Result<()> {
+    let parsed = schedules
+        .iter()
+        .map(|s| s.parse())
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    UPDATER_STATE.lock().unwrap().schedules = schedules
+        .iter()
+        .zip(&parsed)
+        .map(|(config, schedule)| config.initial_state(schedule))
+        .collect();
+    loop {
+        for (idx, (config, schedule)) in schedules.iter().zip(&parsed).enumerate() {
+            let now = Utc::now();
+            let due = UPDATER_STATE.lock().unwrap().schedules[idx]
+                .next_run
+                .map(|next_run| next_run <= now)
+                .unwrap_or(false);
+            if !due {
+                continue;
+            }
+            log::info!("Scheduled update of profile {}", config.profile);
+            let result = start(
+                Some(&config.profile),
+                &[],
+                !config.only_if_new,
+                TaskTrigger::Schedule,
+            )
+            .await;
+            log::info!("Scheduled update of {}: {}", config.profile, result);
+            let mut updater_state = UPDATER_STATE.lock().unwrap();
+            let state = &mut updater_state.schedules[idx];
+            state.last_run = Some(now);
+            state.last_result = Some(result);
+            state.next_run = config.next_run(schedule, Utc::now());
+        }
+        tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
+    }
+}
+
+/// Picks up tasks left unfinished by a previous run of the updater, before any new one can start.
+/// They ran side by side before the restart, so they are not checked for overlaps again.
+fn handle_interrupted_tasks() {
+    let mut updater_state = UPDATER_STATE.lock().unwrap();
+    let mut state_dirs = vec![default_state_dir()];
+    for profile in updater_state.config.profiles.values() {
+        if !state_dirs.contains(&profile.state_dir) {
+            state_dirs.push(profile.state_dir.clone());
+        }
+    }
+    for state in find_interrupted_tasks(&state_dirs) {
+        let task_id = state.profile_name.clone();
+        let mut update_task = UpdateTask::from_interrupted(state);
+        if let Err(e) = update_task.run() {
+            log::error!("Failed to pick up interrupted task {}: {}", task_id, e);
+            continue;
+        }
+        updater_state.tasks.insert(task_id, update_task);
+    }
+}
+
 #[actix_web::main]
 async fn main() -> anyhow::Result<()> {
     let _ = dotenv::dotenv();
@@ -416,6 +504,25 @@ async fn main() -> anyhow::Result<()> {
     //needed for systemctl library
     env::set_var("SYSTEMCTL_PATH", &cli.systemctl_path);
 
+    if let Some(config_path) = &cli.config {
+        let config = Config::load(config_path)?;
+        log::info!(
+            "Loaded config {} with profiles: {:?}",
+            config_path.display(),
+            config.profiles.keys().collect::<Vec<_>>()
+        );
+        UPDATER_STATE.lock().unwrap().config = config;
+    }
+
+    if cli.plan {
+        let profile = resolve_profile(cli.profile.as_deref(), &[])?;
+        let plan = web::block(move || build_plan(&profile)).await?;
+        println!("{}", serde_json::to_string_pretty(&plan)?);
+        return Ok(());
+    }
+
+    handle_interrupted_tasks();
+
     task::spawn(async move {
         match update_loop().await {
             Ok(_) => (),
@@ -423,6 +530,16 @@ async fn main() -> anyhow::Result<()> {
         }
     });
 
+    let schedules = UPDATER_STATE.lock().unwrap().config.schedules.clone();
+    if !schedules.is_empty() {
+        task::spawn(async move {
+            match scheduler_loop(schedules).await {
+                Ok(_) => (),
+                Err(e) => log::error!("Error in scheduler loop: {}", e),
+            }
+        });
+    }
+
     log::info!(
         "Starting update server: {}:{}",
         cli.listen_addr,
@@ -434,11 +551,22 @@ async fn main() -> anyhow::Result<()> {
             .route("/", web::get().to(HttpResponse::Ok))
             .service(greet)
             .service(start_update)
+            .service(start_update_profile)
+            .service(plan_update)
+            .service(plan_update_profile)
+            .service(check_update)
+            .service(check_update_profile)
             .service(progress_endpoint)
+            .service(progress_task_endpoint)
+            .service(history_endpoint)
+            .service(history_entry_endpoint)
             .service(start_update_debug)
             .service(pause_update)
+            .service(pause_update_task)
             .service(resume_update)
+            .service(resume_update_task)
             .service(abort_update)
+            .service(abort_update_task)
     })
     .workers(1)
     .bind((cli.listen_addr, cli.listen_port))
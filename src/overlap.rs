@@ -0,0 +1,221 @@
+use std::env;
+use std::path::{Path, PathBuf};
+
+use crate::config::Profile;
+use crate::guard::canonicalize_lenient;
+use crate::pipeline::Step;
+use crate::removal::is_glob;
+
+/// Directory a `delete_dirs` entry is confined to, the part before its first wildcard
+fn glob_base(path: &Path) -> PathBuf {
+    path.components()
+        .take_while(|c| !is_glob(Path::new(c.as_os_str())))
+        .collect()
+}
+
+/// Absolute path with symlinks resolved, so aliases of the same tree compare equal
+fn resolve(path: &Path) -> PathBuf {
+    let absolute = match env::current_dir() {
+        Ok(current_dir) if path.is_relative() => current_dir.join(path),
+        _ => path.to_path_buf(),
+    };
+    canonicalize_lenient(&absolute).unwrap_or(absolute)
+}
+
+/// Paths written, removed or chowned by the steps of `profile`, resolved
+fn touched_paths(profile: &Profile) -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    for step in &profile.steps {
+        match step {
+            Step::RemovePaths => {
+                paths.extend(profile.delete_dirs.iter().map(|path| glob_base(path)));
+                paths.extend(profile.backup_dir.iter().cloned());
+            }
+            Step::Download => paths.push(profile.output_dir.clone()),
+            Step::Chown => {
+                paths.extend(profile.change_owner_paths.iter().cloned());
+                paths.extend(profile.permissions.iter().map(|rule| rule.path.clone()));
+            }
+            _ => {}
+        }
+    }
+    paths.iter().map(|path| resolve(path)).collect()
+}
+
+fn touched_services(profile: &Profile) -> &[String] {
+    let manages_services = profile
+        .steps
+        .iter()
+        .any(|step| matches!(step, Step::StopServices | Step::StartServices));
+    if manages_services {
+        &profile.services_to_stop
+    } else {
+        &[]
+    }
+}
+
+/// Services `profile` stops together with everything they depend on, directly or not
+fn related_services(profile: &Profile) -> Vec<String> {
+    let mut related: Vec<String> = touched_services(profile).to_vec();
+    let mut idx = 0;
+    while idx < related.len() {
+        if let Some(dependencies) = profile.service_dependencies.get(&related[idx]) {
+            for dependency in dependencies {
+                if !related.contains(dependency) {
+                    related.push(dependency.clone());
+                }
+            }
+        }
+        idx += 1;
+    }
+    related
+}
+
+/// Services stopped by one profile that the other one stops or depends on
+fn service_overlaps(profile: &Profile, other: &Profile) -> Vec<String> {
+    let mut overlaps = Vec::new();
+    for (stopping, stopped, related) in [
+        (profile, touched_services(profile), related_services(other)),
+        (other, touched_services(other), related_services(profile)),
+    ] {
+        for service in stopped {
+            if related.contains(service) {
+                overlaps.push(format!("service {} stopped by {}", service, stopping.name));
+            }
+        }
+    }
+    overlaps
+}
+
+/// Services and paths both profiles work on. A path overlaps with its ancestors and descendants,
+/// a stopped service with the services of the other profile and what they depend on.
+pub fn find_overlaps(profile: &Profile, other: &Profile) -> Vec<String> {
+    let mut overlaps = service_overlaps(profile, other);
+    let other_paths = touched_paths(other);
+    for path in touched_paths(profile) {
+        for other_path in &other_paths {
+            if path.starts_with(other_path) || other_path.starts_with(&path) {
+                overlaps.push(format!(
+                    "path {} and {}",
+                    path.display(),
+                    other_path.display()
+                ));
+            }
+        }
+    }
+    overlaps
+}
+
+/// Refuses `profile` if it would work on anything one of the `running` profiles works on
+pub fn check_overlaps<'a>(
+    profile: &Profile,
+    running: impl IntoIterator<Item = &'a Profile>,
+) -> anyhow::Result<()> {
+    for other in running {
+        let overlaps = find_overlaps(profile, other);
+        if !overlaps.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Overlaps with running task {}: {}",
+                other.name,
+                overlaps.join(", ")
+            ));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::symlink;
+
+    use super::*;
+
+    /// Default pipeline, stopping `name` and downloading into `/data/<name>`
+    fn profile(name: &str, extra: &str) -> Profile {
+        let mut profile: Profile = toml::from_str(&format!(
+            "archive_url = \"http://localhost/{name}.tar.lz4\"\n\
+             output_dir = \"/data/{name}\"\n\
+             services_to_stop = [\"{name}\"]\n\
+             {extra}"
+        ))
+        .unwrap();
+        profile.name = name.into();
+        profile
+    }
+
+    #[test]
+    fn separate_services_and_paths_do_not_overlap() {
+        assert!(find_overlaps(&profile("erigon", ""), &profile("beacon", "")).is_empty());
+    }
+
+    #[test]
+    fn same_service_overlaps() {
+        let mut other = profile("other", "");
+        other.services_to_stop = vec!["erigon".into()];
+        let overlaps = find_overlaps(&profile("erigon", ""), &other);
+        assert!(
+            overlaps.contains(&"service erigon stopped by erigon".to_string()),
+            "{:?}",
+            overlaps
+        );
+    }
+
+    #[test]
+    fn stopping_a_dependency_overlaps() {
+        let beacon = profile("beacon", "[service_dependencies]\nbeacon = [\"erigon\"]");
+        assert_eq!(
+            find_overlaps(&profile("erigon", ""), &beacon),
+            vec!["service erigon stopped by erigon".to_string()]
+        );
+    }
+
+    #[test]
+    fn nested_paths_overlap() {
+        let parent = profile("parent", "delete_dirs = [\"/data\"]");
+        assert!(!find_overlaps(&parent, &profile("beacon", "")).is_empty());
+    }
+
+    #[test]
+    fn glob_overlaps_from_its_base_directory() {
+        let erigon = profile("erigon", "delete_dirs = [\"/data/shared/*/erigon\"]");
+        let beacon = profile("beacon", "change_owner_paths = [\"/data/shared/beacon\"]");
+        assert!(!find_overlaps(&erigon, &beacon).is_empty());
+    }
+
+    #[test]
+    fn relative_paths_are_resolved() {
+        let current_dir = env::current_dir().unwrap();
+        let relative = profile(
+            "relative",
+            "delete_dirs = [\"pipe_updater_overlap/erigon\"]",
+        );
+        let absolute = profile(
+            "absolute",
+            &format!(
+                "change_owner_paths = [\"{}\"]",
+                current_dir.join("pipe_updater_overlap").display()
+            ),
+        );
+        assert!(!find_overlaps(&relative, &absolute).is_empty());
+    }
+
+    #[test]
+    fn symlinked_paths_are_resolved() {
+        let base = env::temp_dir().join(format!("pipe_updater_overlap_{}", std::process::id()));
+        let _ = fs::remove_dir_all(&base);
+        fs::create_dir_all(base.join("real")).unwrap();
+        symlink(base.join("real"), base.join("link")).unwrap();
+        let via_link = profile(
+            "via_link",
+            &format!("delete_dirs = [\"{}\"]", base.join("link/erigon").display()),
+        );
+        let direct = profile(
+            "direct",
+            &format!("change_owner_paths = [\"{}\"]", base.join("real").display()),
+        );
+        let overlaps = find_overlaps(&via_link, &direct);
+        fs::remove_dir_all(&base).unwrap();
+        assert!(!overlaps.is_empty());
+    }
+}
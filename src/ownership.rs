@@ -0,0 +1,371 @@
+use std::fs::{self, Metadata, Permissions};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use glob::{MatchOptions, Pattern};
+use nix::unistd::{fchownat, FchownatFlags, Gid, Group, Uid, User};
+use rayon::prelude::*;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+/// Only the first failures are kept, a tree with broken permissions can produce millions
+const MAX_REPORTED_FAILURES: usize = 100;
+/// Number of failed paths listed in the step error
+const MAX_FAILURES_IN_ERROR: usize = 10;
+
+fn parse_mode(mode: &str) -> Result<u32, String> {
+    match u32::from_str_radix(mode, 8) {
+        Ok(mode) if mode <= 0o7777 => Ok(mode),
+        _ => Err(format!("invalid octal mode: {}", mode)),
+    }
+}
+
+fn deserialize_mode<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u32, D::Error> {
+    parse_mode(&String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+}
+
+fn deserialize_optional_mode<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<u32>, D::Error> {
+    Option::<String>::deserialize(deserializer)?
+        .map(|mode| parse_mode(&mode).map_err(serde::de::Error::custom))
+        .transpose()
+}
+
+fn serialize_mode<S: Serializer>(mode: &u32, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&format!("{:04o}", mode))
+}
+
+fn serialize_optional_mode<S: Serializer>(
+    mode: &Option<u32>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    match mode {
+        Some(mode) => serialize_mode(mode, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn serialize_pattern<S: Serializer>(pattern: &Pattern, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(pattern.as_str())
+}
+
+fn deserialize_pattern<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Pattern, D::Error> {
+    let pattern = String::deserialize(deserializer)?;
+    Pattern::new(&pattern)
+        .map_err(|e| serde::de::Error::custom(format!("invalid pattern {}: {}", pattern, e)))
+}
+
+/// Mode for entries whose path relative to the rule `path` matches `pattern`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ModeOverride {
+    #[serde(
+        serialize_with = "serialize_pattern",
+        deserialize_with = "deserialize_pattern"
+    )]
+    pub pattern: Pattern,
+    #[serde(
+        serialize_with = "serialize_mode",
+        deserialize_with = "deserialize_mode"
+    )]
+    pub mode: u32,
+}
+
+/// Modes set by the `chown` step on a tree once ownership is changed.
+/// The first matching override wins, symlinks are left alone:
+///
+/// ```toml
+/// [[profiles.erigon.permissions]]
+/// path = "/data/erigon"
+/// dir_mode = "0750"
+/// file_mode = "0640"
+/// overrides = [
+///     { pattern = "nodekey", mode = "0600" },
+///     { pattern = "keystore/**", mode = "0600" },
+/// ]
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PermissionRule {
+    pub path: PathBuf,
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_mode",
+        deserialize_with = "deserialize_optional_mode"
+    )]
+    pub dir_mode: Option<u32>,
+    #[serde(
+        default,
+        serialize_with = "serialize_optional_mode",
+        deserialize_with = "deserialize_optional_mode"
+    )]
+    pub file_mode: Option<u32>,
+    #[serde(default)]
+    pub overrides: Vec<ModeOverride>,
+}
+
+impl PermissionRule {
+    fn mode_for(&self, path: &Path, metadata: &Metadata) -> Option<u32> {
+        if metadata.file_type().is_symlink() {
+            return None;
+        }
+        let relative = path.strip_prefix(&self.path).unwrap_or(path);
+        let options = MatchOptions {
+            require_literal_separator: true,
+            ..MatchOptions::new()
+        };
+        self.overrides
+            .iter()
+            .find(|o| o.pattern.matches_path_with(relative, options))
+            .map(|o| o.mode)
+            .or(if metadata.is_dir() {
+                self.dir_mode
+            } else {
+                self.file_mode
+            })
+    }
+
+    fn apply(&self, path: &Path, metadata: &Metadata) -> Result<(), String> {
+        match self.mode_for(path, metadata) {
+            Some(mode) if metadata.permissions().mode() & 0o7777 != mode => {
+                fs::set_permissions(path, Permissions::from_mode(mode)).map_err(|e| e.to_string())
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChownFailure {
+    pub path: PathBuf,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChownProgress {
+    pub files_total: u64,
+    pub files_processed: u64,
+    pub failed_count: u64,
+    pub failures: Vec<ChownFailure>,
+}
+
+impl ChownProgress {
+    fn record(&mut self, path: &Path, result: Result<(), String>) {
+        self.files_processed += 1;
+        if let Err(error) = result {
+            self.fail(path, error);
+        }
+    }
+
+    fn fail(&mut self, path: &Path, error: String) {
+        log::warn!("Failed to change {}: {}", path.display(), error);
+        self.failed_count += 1;
+        if self.failures.len() < MAX_REPORTED_FAILURES {
+            self.failures.push(ChownFailure {
+                path: path.to_path_buf(),
+                error,
+            });
+        }
+    }
+}
+
+/// Accepts names as well as numeric ids
+fn resolve_owner(user: &str, group: &str) -> anyhow::Result<(Uid, Gid)> {
+    let uid = match user.parse() {
+        Ok(uid) => Uid::from_raw(uid),
+        Err(_) => {
+            User::from_name(user)
+                .map_err(|e| anyhow::anyhow!("Failed to look up user {}: {}", user, e))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown user: {}", user))?
+                .uid
+        }
+    };
+    let gid = match group.parse() {
+        Ok(gid) => Gid::from_raw(gid),
+        Err(_) => {
+            Group::from_name(group)
+                .map_err(|e| anyhow::anyhow!("Failed to look up group {}: {}", group, e))?
+                .ok_or_else(|| anyhow::anyhow!("Unknown group: {}", group))?
+                .gid
+        }
+    };
+    Ok((uid, gid))
+}
+
+/// Number of entries `walk_tree` is going to visit, unreadable directories count as one
+fn count_entries(path: &Path) -> u64 {
+    let is_dir = fs::symlink_metadata(path)
+        .map(|m| m.is_dir())
+        .unwrap_or(false);
+    if !is_dir {
+        return 1;
+    }
+    let children = match fs::read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok().map(|e| e.path()))
+            .collect::<Vec<_>>(),
+        Err(_) => return 1,
+    };
+    1 + children
+        .par_iter()
+        .map(|child| count_entries(child))
+        .sum::<u64>()
+}
+
+/// Runs `action` on `path` and everything below it, symlinks are visited but not followed
+fn walk_tree(
+    path: &Path,
+    action: &(impl Fn(&Path, &Metadata) -> Result<(), String> + Sync),
+    progress: &Mutex<ChownProgress>,
+) {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => {
+            progress.lock().unwrap().record(path, Err(e.to_string()));
+            return;
+        }
+    };
+    progress
+        .lock()
+        .unwrap()
+        .record(path, action(path, &metadata));
+    if !metadata.is_dir() {
+        return;
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) => {
+            progress.lock().unwrap().fail(path, e.to_string());
+            return;
+        }
+    };
+    let children = entries
+        .filter_map(|entry| match entry {
+            Ok(entry) => Some(entry.path()),
+            Err(e) => {
+                progress.lock().unwrap().fail(path, e.to_string());
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    children
+        .par_iter()
+        .for_each(|child| walk_tree(child, action, progress));
+}
+
+/// Recursively changes the owner of every path in `target_paths` (when `owner` is given)
+/// and then applies the mode `rules`, failing with the paths that could not be changed
+pub fn change_ownership(
+    owner: Option<(&str, &str)>,
+    target_paths: &[PathBuf],
+    rules: &[PermissionRule],
+    progress: &Arc<Mutex<ChownProgress>>,
+) -> anyhow::Result<()> {
+    let chown_paths = match owner {
+        Some(_) => target_paths
+            .iter()
+            .filter(|p| p.symlink_metadata().is_ok())
+            .collect::<Vec<_>>(),
+        None => vec![],
+    };
+    // with nothing to chown the accounts do not have to exist on this host
+    let owner = match owner {
+        Some((user, group)) if !chown_paths.is_empty() => {
+            Some((user, group, resolve_owner(user, group)?))
+        }
+        _ => None,
+    };
+    let rules = rules
+        .iter()
+        .filter(|r| r.path.symlink_metadata().is_ok())
+        .collect::<Vec<_>>();
+    progress.lock().unwrap().files_total = chown_paths
+        .iter()
+        .map(|p| p.as_path())
+        .chain(rules.iter().map(|r| r.path.as_path()))
+        .map(count_entries)
+        .sum();
+
+    if let Some((user, group, (uid, gid))) = owner {
+        for target_path in chown_paths {
+            log::info!(
+                "Changing path ownership: {} to {}:{}",
+                target_path.display(),
+                user,
+                group
+            );
+            let chown = |path: &Path, _: &Metadata| {
+                fchownat(
+                    None,
+                    path,
+                    Some(uid),
+                    Some(gid),
+                    FchownatFlags::NoFollowSymlink,
+                )
+                .map_err(|e| format!("owner: {}", e))
+            };
+            walk_tree(target_path, &chown, progress);
+        }
+    }
+    for rule in rules {
+        log::info!(
+            "Changing modes under {}: dirs {:?}, files {:?}, {} overrides",
+            rule.path.display(),
+            rule.dir_mode.map(|m| format!("{:o}", m)),
+            rule.file_mode.map(|m| format!("{:o}", m)),
+            rule.overrides.len()
+        );
+        let chmod = |path: &Path, metadata: &Metadata| {
+            rule.apply(path, metadata)
+                .map_err(|e| format!("mode: {}", e))
+        };
+        walk_tree(&rule.path, &chmod, progress);
+    }
+
+    let progress = progress.lock().unwrap();
+    if progress.failed_count == 0 {
+        return Ok(());
+    }
+    let listed = progress
+        .failures
+        .iter()
+        .take(MAX_FAILURES_IN_ERROR)
+        .map(|f| format!("{} ({})", f.path.display(), f.error))
+        .collect::<Vec<_>>();
+    Err(anyhow::anyhow!(
+        "Failed to change ownership or mode of {} paths: {}{}",
+        progress.failed_count,
+        listed.join(", "),
+        if progress.failed_count as usize > listed.len() {
+            ", ..."
+        } else {
+            ""
+        }
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MISSING_ACCOUNT: (&str, &str) =
+        ("pipe-updater-no-such-user", "pipe-updater-no-such-group");
+
+    #[test]
+    fn skips_owner_lookup_without_paths_to_chown() {
+        let progress = Arc::new(Mutex::new(ChownProgress::default()));
+        let missing = [PathBuf::from("/pipe-updater-no-such-path")];
+        assert!(change_ownership(Some(MISSING_ACCOUNT), &[], &[], &progress).is_ok());
+        assert!(change_ownership(Some(MISSING_ACCOUNT), &missing, &[], &progress).is_ok());
+    }
+
+    #[test]
+    fn fails_for_unknown_owner_of_existing_path() {
+        let progress = Arc::new(Mutex::new(ChownProgress::default()));
+        let existing = [std::env::temp_dir()];
+        assert!(change_ownership(Some(MISSING_ACCOUNT), &existing, &[], &progress).is_err());
+    }
+}
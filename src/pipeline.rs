@@ -0,0 +1,76 @@
+use serde::{Deserialize, Serialize};
+
+use crate::stage::Stage;
+
+/// Single step of an update, profiles declare them as an ordered list:
+///
+/// ```toml
+/// steps = [
+///     { type = "download" },
+///     { type = "stop_services" },
+///     { type = "run_command", command = ["/usr/local/bin/export-keys"], timeout_secs = 60 },
+///     { type = "start_services" },
+/// ]
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Step {
+    StopServices,
+    RemovePaths,
+    Download,
+    Chown,
+    StartServices,
+    RunCommand {
+        command: Vec<String>,
+        timeout_secs: Option<u64>,
+    },
+    Wait {
+        seconds: u64,
+    },
+}
+
+impl Step {
+    pub fn stage(&self) -> Stage {
+        match self {
+            Step::StopServices => Stage::StoppingServices,
+            Step::RemovePaths => Stage::RemovingOldFiles,
+            Step::Download => Stage::Downloading,
+            Step::Chown => Stage::ChangingOwnership,
+            Step::StartServices => Stage::StartingService,
+            Step::RunCommand { .. } => Stage::RunningCommand,
+            Step::Wait { .. } => Stage::Waiting,
+        }
+    }
+}
+
+/// Order used before pipelines were configurable
+pub fn default_pipeline() -> Vec<Step> {
+    vec![
+        Step::StopServices,
+        Step::RemovePaths,
+        //let system see that the directories are removed
+        Step::Wait { seconds: 1 },
+        Step::Download,
+        Step::Chown,
+        Step::StartServices,
+    ]
+}
+
+pub fn validate_pipeline(steps: &[Step]) -> anyhow::Result<()> {
+    if steps.is_empty() {
+        return Err(anyhow::anyhow!("Pipeline has no steps"));
+    }
+    for step in steps {
+        if let Step::RunCommand { command, .. } = step {
+            if command.is_empty() {
+                return Err(anyhow::anyhow!("run_command step with empty command"));
+            }
+        }
+    }
+    if steps.iter().filter(|s| **s == Step::Download).count() > 1 {
+        return Err(anyhow::anyhow!(
+            "Pipeline can contain only one download step"
+        ));
+    }
+    Ok(())
+}
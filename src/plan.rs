@@ -0,0 +1,181 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::Serialize;
+
+use crate::config::Profile;
+use crate::guard::check_removal_paths;
+use crate::pipeline::Step;
+use crate::preflight::{check_disk_space, DiskSpaceCheck};
+use crate::remote::{fetch_archive_info, ArchiveInfo};
+use crate::removal::expand_removal_targets;
+use crate::services::stop_groups;
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServicePlan {
+    pub name: String,
+    pub active: Option<bool>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovePlan {
+    pub path: PathBuf,
+    pub exists: bool,
+    pub size: Option<u64>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchivePlan {
+    pub url: String,
+    pub output_dir: PathBuf,
+    pub info: Option<ArchiveInfo>,
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChownPlan {
+    pub path: PathBuf,
+    pub user: String,
+    pub group: String,
+}
+
+/// What an update with a given profile would do, built without changing anything
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpdatePlan {
+    pub steps: Vec<Step>,
+    pub services: Vec<ServicePlan>,
+    /// Groups stopped one after another, services within a group in parallel
+    pub stop_order: Vec<Vec<String>>,
+    pub service_order_error: Option<String>,
+    /// `delete_dirs` with glob patterns expanded, sizes ignore `delete_keep`
+    pub paths_to_remove: Vec<RemovePlan>,
+    pub delete_keep: Vec<String>,
+    pub removal_error: Option<String>,
+    pub backup_dir: Option<PathBuf>,
+    pub archive: ArchivePlan,
+    pub chown: Vec<ChownPlan>,
+    pub disk_space: Option<DiskSpaceCheck>,
+    pub disk_space_error: Option<String>,
+}
+
+/// Total size of a file or a directory tree, symlinks are not followed
+pub fn path_size(path: &Path) -> std::io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    if !metadata.is_dir() {
+        return Ok(metadata.len());
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += path_size(&entry?.path())?;
+    }
+    Ok(total)
+}
+
+/// Blocking, queries systemd, the file system and the archive server
+pub fn build_plan(profile: &Profile) -> UpdatePlan {
+    let services = profile
+        .services_to_stop
+        .iter()
+        .map(|name| match systemctl::is_active(name) {
+            Ok(active) => ServicePlan {
+                name: name.clone(),
+                active: Some(active),
+                error: None,
+            },
+            Err(e) => ServicePlan {
+                name: name.clone(),
+                active: None,
+                error: Some(e.to_string()),
+            },
+        })
+        .collect();
+    let (stop_order, service_order_error) =
+        match stop_groups(&profile.services_to_stop, &profile.service_dependencies) {
+            Ok(groups) => (groups, None),
+            Err(e) => (vec![], Some(e.to_string())),
+        };
+
+    let (targets, expand_error) = match expand_removal_targets(&profile.delete_dirs) {
+        Ok(targets) => (targets, None),
+        Err(e) => (vec![], Some(e.to_string())),
+    };
+    let paths_to_remove = targets
+        .iter()
+        .map(|path| {
+            let exists = path.symlink_metadata().is_ok();
+            let (size, error) = if exists {
+                match path_size(path) {
+                    Ok(size) => (Some(size), None),
+                    Err(e) => (None, Some(e.to_string())),
+                }
+            } else {
+                (None, None)
+            };
+            RemovePlan {
+                path: path.clone(),
+                exists,
+                size,
+                error,
+            }
+        })
+        .collect();
+
+    let removal_error = expand_error.or_else(|| {
+        check_removal_paths(&targets, &profile.removal_roots)
+            .err()
+            .map(|e| e.to_string())
+    });
+
+    let (info, error) = match fetch_archive_info(&profile.archive_url) {
+        Ok(info) => (Some(info), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let archive_size = profile
+        .archive_size
+        .or_else(|| info.as_ref().and_then(|i| i.size));
+    let (disk_space, disk_space_error) = match check_disk_space(profile, archive_size) {
+        Ok(check) => (Some(check), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+
+    let chown = match (&profile.target_user, &profile.target_group) {
+        (Some(user), Some(group)) => profile
+            .change_owner_paths
+            .iter()
+            .map(|path| ChownPlan {
+                path: path.clone(),
+                user: user.clone(),
+                group: group.clone(),
+            })
+            .collect(),
+        _ => vec![],
+    };
+
+    UpdatePlan {
+        steps: profile.steps.clone(),
+        services,
+        stop_order,
+        service_order_error,
+        paths_to_remove,
+        removal_error,
+        delete_keep: profile.delete_keep.clone(),
+        backup_dir: profile.backup_dir.clone(),
+        archive: ArchivePlan {
+            url: profile.archive_url.clone(),
+            output_dir: profile.output_dir.clone(),
+            info,
+            error,
+        },
+        chown,
+        disk_space,
+        disk_space_error,
+    }
+}
@@ -0,0 +1,139 @@
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+use nix::sys::statvfs::statvfs;
+use serde::Serialize;
+
+use crate::config::Profile;
+use crate::guard::check_removal_paths;
+use crate::pipeline::Step;
+use crate::plan::path_size;
+use crate::remote::fetch_archive_info;
+use crate::removal::{compile_keep_patterns, expand_removal_targets, kept_size};
+use crate::stream::check_format;
+
+/// Comparison of the space needed by the archive with what is free under `output_dir`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DiskSpaceCheck {
+    pub filesystem_path: PathBuf,
+    pub available: u64,
+    pub freed_by_removal: u64,
+    pub archive_size: Option<u64>,
+    pub expansion_ratio: f64,
+    pub required: Option<u64>,
+    pub sufficient: Option<bool>,
+}
+
+/// `output_dir` usually does not exist before the first download
+fn existing_ancestor(path: &Path) -> PathBuf {
+    path.ancestors()
+        .find(|p| !p.as_os_str().is_empty() && p.exists())
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| PathBuf::from("."))
+}
+
+fn available_space(path: &Path) -> anyhow::Result<u64> {
+    let stat = statvfs(path)
+        .map_err(|e| anyhow::anyhow!("Failed to stat filesystem of {}: {}", path.display(), e))?;
+    Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+pub fn check_disk_space(
+    profile: &Profile,
+    archive_size: Option<u64>,
+) -> anyhow::Result<DiskSpaceCheck> {
+    let filesystem_path = existing_ancestor(&profile.output_dir);
+    let available = available_space(&filesystem_path)?;
+    let device = filesystem_path.metadata()?.dev();
+
+    // moved aside data is only deleted after the update succeeded, so it frees nothing
+    let mut freed_by_removal = 0;
+    if profile.backup_dir.is_none() && profile.steps.contains(&Step::RemovePaths) {
+        let keep = compile_keep_patterns(&profile.delete_keep)?;
+        for path in &expand_removal_targets(&profile.delete_dirs)? {
+            match path.symlink_metadata() {
+                Ok(metadata) if metadata.dev() == device => {
+                    let size_error = |e: std::io::Error| {
+                        anyhow::anyhow!("Failed to compute size of {}: {}", path.display(), e)
+                    };
+                    // entries matching delete_keep stay on disk
+                    let removed = path_size(path)
+                        .map_err(size_error)?
+                        .saturating_sub(kept_size(path, path, &keep).map_err(size_error)?);
+                    freed_by_removal += removed;
+                }
+                _ => {}
+            }
+        }
+    }
+
+    let required = archive_size.map(|size| (size as f64 * profile.expansion_ratio).ceil() as u64);
+    Ok(DiskSpaceCheck {
+        filesystem_path,
+        available,
+        freed_by_removal,
+        archive_size,
+        expansion_ratio: profile.expansion_ratio,
+        required,
+        sufficient: required.map(|required| available + freed_by_removal >= required),
+    })
+}
+
+/// Checks run before a task is created, an error means the update is refused.
+/// Blocking, must not be called directly from async handlers.
+pub fn preflight(profile: &Profile) -> anyhow::Result<()> {
+    if profile.steps.contains(&Step::RemovePaths) {
+        // moving aside works on whole paths, kept entries would end up in the backup
+        if !profile.delete_keep.is_empty() && profile.backup_dir.is_some() {
+            return Err(anyhow::anyhow!(
+                "delete_keep cannot be combined with backup_dir"
+            ));
+        }
+        check_removal_paths(
+            &expand_removal_targets(&profile.delete_dirs)?,
+            &profile.removal_roots,
+        )?;
+    }
+    if !profile.steps.contains(&Step::Download) {
+        return Ok(());
+    }
+    // verified downloads are unpacked by the updater itself, not by pipe_downloader
+    let verified = profile.checksum.is_some()
+        || profile.checksum_sidecar
+        || !profile.signature_public_keys.is_empty();
+    if verified {
+        check_format(&profile.archive_url)?;
+    }
+    let archive_size = match profile.archive_size {
+        Some(size) => Some(size),
+        // some servers refuse HEAD requests, that is no reason to refuse the update
+        None => match fetch_archive_info(&profile.archive_url) {
+            Ok(info) => info.size,
+            Err(e) => {
+                log::warn!("Cannot read archive size: {}", e);
+                None
+            }
+        },
+    };
+    let check = check_disk_space(profile, archive_size)?;
+    match check.sufficient {
+        Some(true) => Ok(()),
+        Some(false) => Err(anyhow::anyhow!(
+            "Not enough disk space on {}: {} bytes required ({} bytes archive x {}), {} bytes available and {} bytes freed by removal",
+            check.filesystem_path.display(),
+            check.required.unwrap_or_default(),
+            check.archive_size.unwrap_or_default(),
+            check.expansion_ratio,
+            check.available,
+            check.freed_by_removal
+        )),
+        None => {
+            log::warn!(
+                "Archive size of {} is unknown, skipping disk space check",
+                profile.archive_url
+            );
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,53 @@
+use std::time::Duration;
+
+use reqwest::header::{CONTENT_LENGTH, ETAG, LAST_MODIFIED};
+use serde::{Deserialize, Serialize};
+
+/// Metadata of the remote archive taken from a HEAD request
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchiveInfo {
+    pub url: String,
+    pub size: Option<u64>,
+    pub etag: Option<String>,
+    pub last_modified: Option<String>,
+}
+
+fn http_client() -> anyhow::Result<reqwest::blocking::Client> {
+    reqwest::blocking::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+        .map_err(|e| anyhow::anyhow!("Failed to create http client: {}", e))
+}
+
+/// Blocking, must not be called directly from async handlers
+pub fn fetch_archive_info(url: &str) -> anyhow::Result<ArchiveInfo> {
+    let response = http_client()?
+        .head(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow::anyhow!("HEAD request to {} failed: {}", url, e))?;
+    let header = |name| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|v| v.to_str().ok())
+            .map(|v| v.to_string())
+    };
+    Ok(ArchiveInfo {
+        url: url.to_string(),
+        size: header(CONTENT_LENGTH).and_then(|v| v.parse().ok()),
+        etag: header(ETAG),
+        last_modified: header(LAST_MODIFIED),
+    })
+}
+
+/// Blocking, fetches a small text file such as a checksum or signature next to the archive
+pub fn fetch_text(url: &str) -> anyhow::Result<String> {
+    http_client()?
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .and_then(|r| r.text())
+        .map_err(|e| anyhow::anyhow!("Failed to fetch {}: {}", url, e))
+}
@@ -0,0 +1,184 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use glob::{MatchOptions, Pattern};
+use serde::Serialize;
+
+use crate::plan::path_size;
+
+/// What the `remove_paths` step deleted and left in place
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RemovalProgress {
+    pub targets: Vec<PathBuf>,
+    pub files_removed: u64,
+    pub bytes_removed: u64,
+    pub kept: Vec<PathBuf>,
+}
+
+pub fn is_glob(path: &Path) -> bool {
+    path.to_string_lossy().contains(['*', '?', '['])
+}
+
+/// Expands glob patterns from `delete_dirs`, entries without wildcards are returned as they are
+pub fn expand_removal_targets(patterns: &[PathBuf]) -> anyhow::Result<Vec<PathBuf>> {
+    let mut targets = Vec::new();
+    for pattern in patterns {
+        if !is_glob(pattern) {
+            if !targets.contains(pattern) {
+                targets.push(pattern.clone());
+            }
+            continue;
+        }
+        let pattern = pattern.to_string_lossy();
+        let matches = glob::glob(&pattern)
+            .map_err(|e| anyhow::anyhow!("Invalid delete pattern {}: {}", pattern, e))?;
+        for path in matches {
+            let path = path.map_err(|e| anyhow::anyhow!("Failed to expand {}: {}", pattern, e))?;
+            if !targets.contains(&path) {
+                targets.push(path);
+            }
+        }
+    }
+    Ok(targets)
+}
+
+/// Keep patterns are relative to each removal target, a trailing `/` is ignored
+pub fn compile_keep_patterns(keep: &[String]) -> anyhow::Result<Vec<Pattern>> {
+    keep.iter()
+        .map(|pattern| {
+            Pattern::new(pattern.trim_end_matches('/'))
+                .map_err(|e| anyhow::anyhow!("Invalid keep pattern {}: {}", pattern, e))
+        })
+        .collect()
+}
+
+fn is_kept(path: &Path, root: &Path, keep: &[Pattern]) -> bool {
+    let relative = match path.strip_prefix(root) {
+        Ok(relative) if !relative.as_os_str().is_empty() => relative,
+        _ => return false,
+    };
+    let options = MatchOptions {
+        require_literal_separator: true,
+        ..MatchOptions::new()
+    };
+    keep.iter()
+        .any(|pattern| pattern.matches_path_with(relative, options))
+}
+
+/// Bytes under `path` that `keep` protects from removal
+pub fn kept_size(path: &Path, root: &Path, keep: &[Pattern]) -> std::io::Result<u64> {
+    if is_kept(path, root, keep) {
+        return path_size(path);
+    }
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(0);
+    }
+    let mut total = 0;
+    for entry in fs::read_dir(path)? {
+        total += kept_size(&entry?.path(), root, keep)?;
+    }
+    Ok(total)
+}
+
+/// Removes `path` except for kept entries, returns whether it is gone completely
+fn prune(
+    path: &Path,
+    root: &Path,
+    keep: &[Pattern],
+    progress: &Mutex<RemovalProgress>,
+) -> anyhow::Result<bool> {
+    if is_kept(path, root, keep) {
+        log::info!("Keeping: {}", path.display());
+        progress.lock().unwrap().kept.push(path.to_path_buf());
+        return Ok(false);
+    }
+    let metadata = fs::symlink_metadata(path)
+        .map_err(|e| anyhow::anyhow!("Error reading {}: {}", path.display(), e))?;
+    if !metadata.is_dir() {
+        fs::remove_file(path)
+            .map_err(|e| anyhow::anyhow!("Error removing file {}: {}", path.display(), e))?;
+        let mut progress = progress.lock().unwrap();
+        progress.files_removed += 1;
+        progress.bytes_removed += metadata.len();
+        return Ok(true);
+    }
+    let entries = fs::read_dir(path)
+        .map_err(|e| anyhow::anyhow!("Error reading directory {}: {}", path.display(), e))?;
+    let mut all_removed = true;
+    for entry in entries {
+        let entry = entry
+            .map_err(|e| anyhow::anyhow!("Error reading directory {}: {}", path.display(), e))?;
+        all_removed &= prune(&entry.path(), root, keep, progress)?;
+    }
+    if all_removed {
+        fs::remove_dir(path)
+            .map_err(|e| anyhow::anyhow!("Error removing directory {}: {}", path.display(), e))?;
+    }
+    Ok(all_removed)
+}
+
+/// Deletes every target except for entries matching `keep`
+pub fn remove_paths(
+    targets: &[PathBuf],
+    keep: &[String],
+    progress: &Arc<Mutex<RemovalProgress>>,
+) -> anyhow::Result<()> {
+    let keep = compile_keep_patterns(keep)?;
+    progress.lock().unwrap().targets = targets.to_vec();
+    for path in targets {
+        if path.symlink_metadata().is_err() {
+            log::info!("Trying to remove, path not exists: {}", path.display());
+            continue;
+        }
+        log::info!("Removing: {}", path.display());
+        prune(path, path, &keep, progress)?;
+    }
+    let progress = progress.lock().unwrap();
+    log::info!(
+        "Removed {} files, {} bytes, kept {} entries",
+        progress.files_removed,
+        progress.bytes_removed,
+        progress.kept.len()
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kept(path: &str, keep: &[&str]) -> bool {
+        let keep = keep.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        is_kept(
+            Path::new(path),
+            Path::new("/data/erigon"),
+            &compile_keep_patterns(&keep).unwrap(),
+        )
+    }
+
+    #[test]
+    fn keeps_entries_matching_relative_patterns() {
+        assert!(kept("/data/erigon/nodekey", &["nodekey"]));
+        assert!(kept("/data/erigon/jwt.hex", &["*.hex"]));
+        assert!(!kept("/data/erigon/chaindata", &["nodekey", "*.hex"]));
+    }
+
+    #[test]
+    fn ignores_trailing_slash_of_directory_patterns() {
+        assert!(kept("/data/erigon/keystore", &["keystore/"]));
+    }
+
+    #[test]
+    fn wildcards_do_not_cross_directories() {
+        assert!(!kept("/data/erigon/nodes/nodekey", &["*nodekey"]));
+        assert!(kept("/data/erigon/nodes/nodekey", &["*/nodekey"]));
+    }
+
+    #[test]
+    fn never_keeps_the_root_or_paths_outside_of_it() {
+        assert!(!kept("/data/erigon", &["*"]));
+        assert!(!kept("/data/beacon/nodekey", &["nodekey"]));
+    }
+}
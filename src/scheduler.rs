@@ -0,0 +1,71 @@
+use std::str::FromStr;
+
+use chrono::{DateTime, Duration, Utc};
+use cron::Schedule;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+
+/// Unattended update of a profile, the cron expression has a seconds field:
+///
+/// ```toml
+/// [[schedules]]
+/// profile = "erigon-mumbai"
+/// cron = "0 0 3 * * Sun"
+/// jitter_secs = 1800
+/// only_if_new = true
+/// ```
+#[derive(Debug, Clone, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduleConfig {
+    pub profile: String,
+    pub cron: String,
+    /// Each run is delayed by a random number of seconds up to this value
+    #[serde(default)]
+    pub jitter_secs: u64,
+    /// Skip the run unless the remote archive differs from the installed one
+    #[serde(default)]
+    pub only_if_new: bool,
+}
+
+/// Schedule as shown in `/progress`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ScheduleState {
+    pub profile: String,
+    pub cron: String,
+    pub only_if_new: bool,
+    pub next_run: Option<DateTime<Utc>>,
+    pub last_run: Option<DateTime<Utc>>,
+    pub last_result: Option<String>,
+}
+
+impl ScheduleConfig {
+    pub fn parse(&self) -> anyhow::Result<Schedule> {
+        Schedule::from_str(&self.cron)
+            .map_err(|e| anyhow::anyhow!("Invalid cron expression {}: {}", self.cron, e))
+    }
+
+    /// First time after `after` the schedule fires, with jitter applied
+    pub fn next_run(&self, schedule: &Schedule, after: DateTime<Utc>) -> Option<DateTime<Utc>> {
+        let jitter = if self.jitter_secs > 0 {
+            rand::thread_rng().gen_range(0..=self.jitter_secs)
+        } else {
+            0
+        };
+        schedule
+            .after(&after)
+            .next()
+            .map(|time| time + Duration::seconds(jitter as i64))
+    }
+
+    pub fn initial_state(&self, schedule: &Schedule) -> ScheduleState {
+        ScheduleState {
+            profile: self.profile.clone(),
+            cron: self.cron.clone(),
+            only_if_new: self.only_if_new,
+            next_run: self.next_run(schedule, Utc::now()),
+            last_run: None,
+            last_result: None,
+        }
+    }
+}
@@ -0,0 +1,315 @@
+use std::collections::HashMap;
+use std::env;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::command::{run_command, CommandOutput};
+
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const SYSTEMCTL_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a unit gets to go down after SIGKILL before the stop is reported as failed
+const KILL_GRACE: Duration = Duration::from_secs(30);
+
+fn default_stop_timeout_secs() -> u64 {
+    300
+}
+
+/// How the `stop_services` step waits for each unit to go down:
+///
+/// ```toml
+/// [profiles.erigon.service_stop]
+/// timeout_secs = 600
+/// kill_on_timeout = true
+/// ```
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct StopPolicy {
+    #[serde(default = "default_stop_timeout_secs")]
+    pub timeout_secs: u64,
+    /// Send SIGKILL to the unit when it is still not inactive after `timeout_secs`
+    #[serde(default)]
+    pub kill_on_timeout: bool,
+}
+
+impl Default for StopPolicy {
+    fn default() -> Self {
+        Self {
+            timeout_secs: default_stop_timeout_secs(),
+            kill_on_timeout: false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ServiceStopResult {
+    pub service: String,
+    /// Last state reported by `systemctl is-active`
+    pub state: String,
+    pub stopped: bool,
+    pub killed: bool,
+    pub elapsed_secs: f64,
+    pub error_message: Option<String>,
+}
+
+fn systemctl(args: &[&str]) -> anyhow::Result<CommandOutput> {
+    let mut command =
+        vec![env::var("SYSTEMCTL_PATH").unwrap_or_else(|_| "/usr/bin/systemctl".into())];
+    command.extend(args.iter().map(|arg| arg.to_string()));
+    run_command(&command, Some(SYSTEMCTL_TIMEOUT))
+}
+
+/// Returns the unit state, `deactivating` still counts as running
+fn unit_state(service: &str) -> anyhow::Result<String> {
+    // is-active exits non-zero for every state but active, only the printed state matters
+    let output = systemctl(&["is-active", service])?;
+    Ok(output.stdout.trim().to_string())
+}
+
+fn is_down(state: &str) -> bool {
+    matches!(state, "inactive" | "failed" | "unknown")
+}
+
+/// Polls the unit until it is down or `timeout` passes, returning its last state
+fn wait_until_down(
+    service: &str,
+    timeout: Duration,
+    results: &Arc<Mutex<Vec<ServiceStopResult>>>,
+    idx: usize,
+    started: Instant,
+) -> anyhow::Result<String> {
+    let waiting_since = Instant::now();
+    loop {
+        let state = unit_state(service)?;
+        {
+            let mut results = results.lock().unwrap();
+            results[idx].state = state.clone();
+            results[idx].elapsed_secs = started.elapsed().as_secs_f64();
+        }
+        if is_down(&state) || waiting_since.elapsed() >= timeout {
+            return Ok(state);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn stop_one(
+    service: &str,
+    policy: &StopPolicy,
+    results: &Arc<Mutex<Vec<ServiceStopResult>>>,
+    idx: usize,
+) -> anyhow::Result<()> {
+    let started = Instant::now();
+    // --no-block so the timeout below is ours and not the unit's TimeoutStopSec
+    let output = systemctl(&["stop", "--no-block", service])?;
+    if !output.success() {
+        return Err(anyhow::anyhow!(output.describe_failure()));
+    }
+    let timeout = Duration::from_secs(policy.timeout_secs);
+    let state = wait_until_down(service, timeout, results, idx, started)?;
+    if is_down(&state) {
+        return Ok(());
+    }
+    if !policy.kill_on_timeout {
+        return Err(anyhow::anyhow!(
+            "Service {} is still {} after {}s",
+            service,
+            state,
+            policy.timeout_secs
+        ));
+    }
+    log::warn!(
+        "Service {} is still {} after {}s, sending SIGKILL",
+        service,
+        state,
+        policy.timeout_secs
+    );
+    results.lock().unwrap()[idx].killed = true;
+    let output = systemctl(&["kill", "--signal=SIGKILL", service])?;
+    if !output.success() {
+        return Err(anyhow::anyhow!(output.describe_failure()));
+    }
+    let state = wait_until_down(service, KILL_GRACE, results, idx, started)?;
+    if is_down(&state) {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(
+            "Service {} is still {} after SIGKILL",
+            service,
+            state
+        ))
+    }
+}
+
+/// Stops `service` and waits until systemd reports it as down, recording the wait in `results`
+pub fn stop_service(
+    service: &str,
+    policy: &StopPolicy,
+    results: &Arc<Mutex<Vec<ServiceStopResult>>>,
+) -> anyhow::Result<()> {
+    log::info!("Stopping service: {}", service);
+    let idx = {
+        let mut results = results.lock().unwrap();
+        results.push(ServiceStopResult {
+            service: service.to_string(),
+            state: "active".into(),
+            stopped: false,
+            killed: false,
+            elapsed_secs: 0.0,
+            error_message: None,
+        });
+        results.len() - 1
+    };
+    let res = stop_one(service, policy, results, idx);
+    let mut results = results.lock().unwrap();
+    match &res {
+        Ok(()) => {
+            log::info!(
+                "Service {} stopped after {:.1}s",
+                service,
+                results[idx].elapsed_secs
+            );
+            results[idx].stopped = true;
+        }
+        Err(e) => {
+            log::error!("Failed to stop service {}: {}", service, e);
+            results[idx].error_message = Some(e.to_string());
+        }
+    }
+    res
+}
+
+/// Splits `services` into groups that can be started one after another, every service
+/// coming after the services it depends on. Services within a group do not depend on each
+/// other. Dependencies on services outside of `services` are ignored.
+pub fn start_groups(
+    services: &[String],
+    dependencies: &HashMap<String, Vec<String>>,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut remaining: Vec<String> = Vec::new();
+    for service in services {
+        if !remaining.contains(service) {
+            remaining.push(service.clone());
+        }
+    }
+    let mut groups: Vec<Vec<String>> = Vec::new();
+    while !remaining.is_empty() {
+        let (ready, blocked): (Vec<_>, Vec<_>) = remaining.iter().cloned().partition(|service| {
+            dependencies
+                .get(service)
+                .map(|deps| deps.iter().all(|dep| !remaining.contains(dep)))
+                .unwrap_or(true)
+        });
+        if ready.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Dependency cycle between services: {}",
+                blocked.join(", ")
+            ));
+        }
+        groups.push(ready);
+        remaining = blocked;
+    }
+    Ok(groups)
+}
+
+/// Reverse of `start_groups`, dependants go down before what they depend on
+pub fn stop_groups(
+    services: &[String],
+    dependencies: &HashMap<String, Vec<String>>,
+) -> anyhow::Result<Vec<Vec<String>>> {
+    let mut groups = start_groups(services, dependencies)?;
+    groups.reverse();
+    Ok(groups)
+}
+
+/// Runs `action` for every service of the group in parallel, failing with all errors if any failed
+pub fn for_each_parallel(
+    group: &[String],
+    action: impl Fn(&str) -> anyhow::Result<()> + Sync,
+) -> anyhow::Result<()> {
+    let action = &action;
+    let errors = thread::scope(|scope| {
+        let handles = group
+            .iter()
+            .map(|service| (service, scope.spawn(move || action(service))))
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .filter_map(|(service, handle)| match handle.join() {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(format!("{}: worker thread panicked", service)),
+            })
+            .collect::<Vec<_>>()
+    });
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow::anyhow!(errors.join(", ")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(services: &[&str]) -> Vec<String> {
+        services.iter().map(|s| s.to_string()).collect()
+    }
+
+    fn groups(groups: &[&[&str]]) -> Vec<Vec<String>> {
+        groups.iter().map(|group| names(group)).collect()
+    }
+
+    #[test]
+    fn independent_services_start_together() {
+        let services = names(&["erigon", "beacon"]);
+        assert_eq!(
+            start_groups(&services, &HashMap::new()).unwrap(),
+            groups(&[&["erigon", "beacon"]])
+        );
+    }
+
+    #[test]
+    fn dependencies_start_first_and_stop_last() {
+        let services = names(&["validator", "beacon", "erigon"]);
+        let dependencies = HashMap::from([
+            ("validator".to_string(), names(&["beacon"])),
+            ("beacon".to_string(), names(&["erigon"])),
+        ]);
+        assert_eq!(
+            start_groups(&services, &dependencies).unwrap(),
+            groups(&[&["erigon"], &["beacon"], &["validator"]])
+        );
+        assert_eq!(
+            stop_groups(&services, &dependencies).unwrap(),
+            groups(&[&["validator"], &["beacon"], &["erigon"]])
+        );
+    }
+
+    #[test]
+    fn ignores_dependencies_outside_of_services_and_duplicates() {
+        let services = names(&["beacon", "beacon"]);
+        let dependencies = HashMap::from([("beacon".to_string(), names(&["erigon"]))]);
+        assert_eq!(
+            start_groups(&services, &dependencies).unwrap(),
+            groups(&[&["beacon"]])
+        );
+    }
+
+    #[test]
+    fn rejects_dependency_cycles() {
+        let services = names(&["erigon", "beacon", "other"]);
+        let dependencies = HashMap::from([
+            ("erigon".to_string(), names(&["beacon"])),
+            ("beacon".to_string(), names(&["erigon"])),
+        ]);
+        let err = start_groups(&services, &dependencies)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("erigon, beacon"), "{}", err);
+    }
+}
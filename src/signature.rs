@@ -0,0 +1,61 @@
+use minisign_verify::{PublicKey, Signature};
+use serde::Serialize;
+
+use crate::checksum::{parse_sidecar, sidecar_url, ExpectedChecksum};
+use crate::remote::fetch_text;
+
+/// Signature check of the archive checksum, shown in `/progress`
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SignatureResult {
+    pub checksum_url: String,
+    pub signature_url: String,
+    /// Index into `signature_public_keys` of the key that verified the signature
+    pub key_index: usize,
+    pub checksum: ExpectedChecksum,
+}
+
+pub fn parse_public_key(key: &str) -> anyhow::Result<PublicKey> {
+    PublicKey::from_base64(key.trim())
+        .map_err(|e| anyhow::anyhow!("Invalid minisign public key {}: {}", key, e))
+}
+
+/// Fetches `<archive_url>.sha256` and its minisign signature `<archive_url>.sha256.minisig`
+/// and returns the checksum once the signature verifies against one of `public_keys`.
+/// The archive itself is then checked against that checksum by the `download` step.
+pub fn verify_signed_checksum(
+    archive_url: &str,
+    public_keys: &[String],
+) -> anyhow::Result<SignatureResult> {
+    let keys = public_keys
+        .iter()
+        .map(|key| parse_public_key(key))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    let checksum_url = sidecar_url(archive_url);
+    let signature_url = format!("{}.minisig", checksum_url);
+    let contents = fetch_text(&checksum_url)?;
+    let signature = Signature::decode(&fetch_text(&signature_url)?)
+        .map_err(|e| anyhow::anyhow!("Invalid signature {}: {}", signature_url, e))?;
+    let key_index = keys
+        .iter()
+        .position(|key| key.verify(contents.as_bytes(), &signature, false).is_ok())
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Signature {} does not verify against any configured public key",
+                signature_url
+            )
+        })?;
+    let checksum = parse_sidecar(&contents)?;
+    log::info!(
+        "Checksum {} signed by key {}: {}",
+        checksum_url,
+        key_index,
+        checksum.hex
+    );
+    Ok(SignatureResult {
+        checksum_url,
+        signature_url,
+        key_index,
+        checksum,
+    })
+}
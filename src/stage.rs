@@ -0,0 +1,385 @@
+use std::fmt;
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Stage {
+    Init,
+    VerifyingSignature,
+    StoppingServices,
+    RemovingOldFiles,
+    Downloading,
+    ChangingOwnership,
+    StartingService,
+    RunningCommand,
+    Waiting,
+    RemovingBackup,
+    RestoringBackup,
+    RecoveringServices,
+    Verifying,
+    Finished,
+    Failed,
+    VerificationFailed,
+}
+
+impl Stage {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Stage::Init => "init",
+            Stage::VerifyingSignature => "verifying_signature",
+            Stage::StoppingServices => "stopping_services",
+            Stage::RemovingOldFiles => "removing_old_files",
+            Stage::Downloading => "downloading",
+            Stage::ChangingOwnership => "changing_ownership",
+            Stage::StartingService => "starting_service",
+            Stage::RunningCommand => "running_command",
+            Stage::Waiting => "waiting",
+            Stage::RemovingBackup => "removing_backup",
+            Stage::RestoringBackup => "restoring_backup",
+            Stage::RecoveringServices => "recovering_services",
+            Stage::Finished => "finished",
+            Stage::Verifying => "verifying",
+            Stage::Failed => "failed",
+            Stage::VerificationFailed => "verification_failed",
+        }
+    }
+
+    pub fn is_terminal(self) -> bool {
+        matches!(
+            self,
+            Stage::Finished | Stage::Failed | Stage::VerificationFailed
+        )
+    }
+
+    /// Stages entered while the profile pipeline runs, as opposed to the recovery and terminal ones
+    pub fn is_pipeline(self) -> bool {
+        matches!(
+            self,
+            Stage::VerifyingSignature
+                | Stage::StoppingServices
+                | Stage::RemovingOldFiles
+                | Stage::Downloading
+                | Stage::ChangingOwnership
+                | Stage::StartingService
+                | Stage::RunningCommand
+                | Stage::Waiting
+                | Stage::Verifying
+        )
+    }
+}
+
+impl fmt::Display for Stage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StageOutcome {
+    Running,
+    Succeeded,
+    Failed,
+    Skipped,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StageRecord {
+    pub stage: Stage,
+    pub started_at: DateTime<Utc>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_secs: Option<f64>,
+    pub outcome: StageOutcome,
+    pub error_message: Option<String>,
+}
+
+impl StageRecord {
+    fn close(&mut self, outcome: StageOutcome, error_message: Option<String>) {
+        let now = Utc::now();
+        self.finished_at = Some(now);
+        self.duration_secs = Some((now - self.started_at).num_milliseconds() as f64 / 1000.0);
+        self.outcome = outcome;
+        self.error_message = error_message;
+    }
+}
+
+/// Current stage of an update task together with the record of every stage it went through
+#[derive(Debug, Clone)]
+pub struct StageTracker {
+    current: Stage,
+    history: Vec<StageRecord>,
+    /// Pipeline stages in the order the profile steps enter them
+    pipeline: Vec<Stage>,
+    /// Index into `pipeline` of the next stage to enter
+    cursor: usize,
+}
+
+impl StageTracker {
+    pub fn new(pipeline: Vec<Stage>) -> Self {
+        Self {
+            current: Stage::Init,
+            history: Vec::new(),
+            pipeline,
+            cursor: 0,
+        }
+    }
+
+    /// Tracker of a task read back from its state file, with nothing left to run
+    /// until `set_pipeline` is called
+    pub fn restore(current: Stage, history: Vec<StageRecord>) -> Self {
+        Self {
+            current,
+            history,
+            pipeline: Vec::new(),
+            cursor: 0,
+        }
+    }
+
+    /// Replaces the stages still to run, used when an interrupted task is resumed
+    pub fn set_pipeline(&mut self, pipeline: Vec<Stage>) {
+        self.pipeline = pipeline;
+        self.cursor = 0;
+    }
+
+    fn visited(&self, stage: Stage) -> bool {
+        self.history.iter().any(|record| record.stage == stage)
+    }
+
+    /// Pipeline stages follow the profile order. Recovery stages only follow what they undo:
+    /// a backup exists once `removing_old_files` ran, services need recovering once
+    /// `stopping_services` ran. Re-entering a recovery stage is allowed, so an
+    /// interrupted recovery can be run again after a restart.
+    fn can_enter(&self, next: Stage) -> bool {
+        let current = self.current;
+        if current.is_terminal() {
+            return false;
+        }
+        let pipeline_done = self.cursor >= self.pipeline.len();
+        let from_pipeline = current == Stage::Init || current.is_pipeline();
+        match next {
+            Stage::Init => false,
+            Stage::Failed => true,
+            Stage::VerificationFailed => {
+                current == Stage::Verifying
+                    || (current == Stage::RecoveringServices && self.visited(Stage::Verifying))
+            }
+            Stage::RestoringBackup => {
+                self.visited(Stage::RemovingOldFiles)
+                    && (from_pipeline || current == Stage::RestoringBackup)
+            }
+            Stage::RecoveringServices => {
+                self.visited(Stage::StoppingServices)
+                    && (from_pipeline
+                        || matches!(current, Stage::RestoringBackup | Stage::RecoveringServices))
+            }
+            Stage::RemovingBackup => {
+                pipeline_done
+                    && self.visited(Stage::RemovingOldFiles)
+                    && (from_pipeline || current == Stage::RemovingBackup)
+            }
+            Stage::Finished => pipeline_done && (from_pipeline || current == Stage::RemovingBackup),
+            _ => from_pipeline && self.pipeline.get(self.cursor) == Some(&next),
+        }
+    }
+
+    pub fn current(&self) -> Stage {
+        self.current
+    }
+
+    pub fn history(&self) -> &[StageRecord] {
+        &self.history
+    }
+
+    fn close_current(&mut self, outcome: StageOutcome, error_message: Option<String>) {
+        if let Some(record) = self.history.last_mut() {
+            if record.outcome == StageOutcome::Running {
+                record.close(outcome, error_message);
+            }
+        }
+    }
+
+    /// Moves to `next`, marking the current stage as succeeded
+    pub fn enter(&mut self, next: Stage) -> anyhow::Result<()> {
+        if !self.can_enter(next) {
+            return Err(anyhow::anyhow!(
+                "Invalid stage transition: {} -> {}",
+                self.current,
+                next
+            ));
+        }
+        log::debug!("Stage transition: {} -> {}", self.current, next);
+        self.close_current(StageOutcome::Succeeded, None);
+        if next.is_pipeline() {
+            self.cursor += 1;
+        }
+        self.current = next;
+        if !next.is_terminal() {
+            self.history.push(StageRecord {
+                stage: next,
+                started_at: Utc::now(),
+                finished_at: None,
+                duration_secs: None,
+                outcome: StageOutcome::Running,
+                error_message: None,
+            });
+        }
+        Ok(())
+    }
+
+    /// Marks the current stage as failed but keeps the task going, so recovery stages can follow
+    pub fn fail_current(&mut self, error_message: &str) {
+        self.close_current(StageOutcome::Failed, Some(error_message.to_string()));
+    }
+
+    /// Marks the current stage as skipped, `reason` is reported as its message
+    pub fn skip_current(&mut self, reason: &str) {
+        self.close_current(StageOutcome::Skipped, Some(reason.to_string()));
+    }
+
+    /// Marks the current stage as failed and moves to `Stage::Failed`
+    pub fn fail(&mut self, error_message: &str) {
+        if self.current.is_terminal() {
+            return;
+        }
+        self.fail_current(error_message);
+        self.current = Stage::Failed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PIPELINE: [Stage; 4] = [
+        Stage::StoppingServices,
+        Stage::RemovingOldFiles,
+        Stage::Downloading,
+        Stage::StartingService,
+    ];
+
+    /// Tracker that went through the first `entered` stages of `PIPELINE`
+    fn tracker(entered: usize) -> StageTracker {
+        let mut tracker = StageTracker::new(PIPELINE.to_vec());
+        for stage in &PIPELINE[..entered] {
+            tracker.enter(*stage).unwrap();
+        }
+        tracker
+    }
+
+    #[test]
+    fn follows_pipeline_order() {
+        let mut tracker = tracker(PIPELINE.len());
+        tracker.enter(Stage::Finished).unwrap();
+        let stages = tracker
+            .history()
+            .iter()
+            .map(|record| (record.stage, record.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            stages,
+            PIPELINE
+                .iter()
+                .map(|stage| (*stage, StageOutcome::Succeeded))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_order_stages() {
+        let mut tracker = tracker(1);
+        assert!(tracker.enter(Stage::Downloading).is_err());
+        assert!(tracker.enter(Stage::StoppingServices).is_err());
+        assert!(tracker.enter(Stage::Waiting).is_err());
+        assert!(tracker.enter(Stage::Init).is_err());
+        assert!(tracker.enter(Stage::Finished).is_err());
+        assert_eq!(tracker.current(), Stage::StoppingServices);
+        tracker.enter(Stage::RemovingOldFiles).unwrap();
+    }
+
+    #[test]
+    fn restores_backup_only_after_removal() {
+        assert!(tracker(1).enter(Stage::RestoringBackup).is_err());
+        let mut tracker = tracker(3);
+        tracker.fail_current("download failed");
+        tracker.enter(Stage::RestoringBackup).unwrap();
+        tracker.enter(Stage::RecoveringServices).unwrap();
+        assert!(tracker.enter(Stage::RestoringBackup).is_err());
+        tracker.enter(Stage::Failed).unwrap();
+        assert_eq!(tracker.history()[2].outcome, StageOutcome::Failed);
+    }
+
+    #[test]
+    fn recovers_services_only_after_stopping_them() {
+        let mut not_stopped = StageTracker::new(vec![Stage::Downloading]);
+        not_stopped.enter(Stage::Downloading).unwrap();
+        assert!(not_stopped.enter(Stage::RecoveringServices).is_err());
+        let mut stopped = tracker(1);
+        stopped.enter(Stage::RecoveringServices).unwrap();
+        assert!(stopped.enter(Stage::RemovingOldFiles).is_err());
+        assert!(stopped.enter(Stage::Finished).is_err());
+        stopped.enter(Stage::Failed).unwrap();
+    }
+
+    #[test]
+    fn removes_backup_once_pipeline_is_done() {
+        assert!(tracker(3).enter(Stage::RemovingBackup).is_err());
+        let mut tracker = tracker(PIPELINE.len());
+        tracker.enter(Stage::RemovingBackup).unwrap();
+        assert!(tracker.enter(Stage::RestoringBackup).is_err());
+        tracker.enter(Stage::Finished).unwrap();
+    }
+
+    #[test]
+    fn verification_fails_only_after_verifying() {
+        let mut tracker = StageTracker::new(vec![Stage::StartingService, Stage::Verifying]);
+        tracker.enter(Stage::StartingService).unwrap();
+        assert!(tracker.enter(Stage::VerificationFailed).is_err());
+        tracker.enter(Stage::Verifying).unwrap();
+        tracker.enter(Stage::VerificationFailed).unwrap();
+    }
+
+    #[test]
+    fn terminal_stages_are_final() {
+        let mut tracker = tracker(PIPELINE.len());
+        tracker.enter(Stage::Finished).unwrap();
+        assert!(tracker.enter(Stage::Failed).is_err());
+        tracker.fail("late error");
+        assert_eq!(tracker.current(), Stage::Finished);
+    }
+
+    #[test]
+    fn resumes_interrupted_stage_with_new_pipeline() {
+        let interrupted = tracker(3);
+        let mut restored =
+            StageTracker::restore(interrupted.current(), interrupted.history().to_vec());
+        restored.fail_current("Interrupted by a restart of the updater");
+        assert!(restored.enter(Stage::Downloading).is_err());
+        restored.set_pipeline(vec![Stage::Downloading, Stage::StartingService]);
+        restored.enter(Stage::Downloading).unwrap();
+        assert!(restored.enter(Stage::Finished).is_err());
+        restored.enter(Stage::StartingService).unwrap();
+        restored.enter(Stage::Finished).unwrap();
+        assert_eq!(restored.history().len(), 5);
+        assert_eq!(restored.history()[2].outcome, StageOutcome::Failed);
+    }
+
+    #[test]
+    fn reenters_interrupted_recovery_stages() {
+        let mut removing = tracker(PIPELINE.len());
+        removing.enter(Stage::RemovingBackup).unwrap();
+        let mut restored = StageTracker::restore(removing.current(), removing.history().to_vec());
+        restored.set_pipeline(Vec::new());
+        restored.enter(Stage::RemovingBackup).unwrap();
+        restored.enter(Stage::Finished).unwrap();
+
+        let mut recovering = tracker(2);
+        recovering.enter(Stage::RecoveringServices).unwrap();
+        let mut restored =
+            StageTracker::restore(recovering.current(), recovering.history().to_vec());
+        restored.enter(Stage::RecoveringServices).unwrap();
+        restored.enter(Stage::Failed).unwrap();
+    }
+}
@@ -0,0 +1,279 @@
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use reqwest::blocking::{Client, Response};
+use reqwest::header::{CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE};
+use reqwest::StatusCode;
+use serde::Serialize;
+
+use crate::checksum::{ChecksumAlgorithm, Hasher};
+
+/// Progress of a download that is hashed while it is unpacked, shown in `/progress`
+#[derive(Debug, Clone, Default, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StreamProgress {
+    /// Set once the download goes through this stream, pipe_downloader stays idle then
+    pub streaming: bool,
+    pub downloaded: u64,
+    pub total: Option<u64>,
+    pub paused: bool,
+}
+
+impl StreamProgress {
+    pub fn human_line(&self) -> String {
+        let mut line = match self.total {
+            Some(total) if total > 0 => format!(
+                "Downloaded {} of {} bytes ({:.1}%)",
+                self.downloaded,
+                total,
+                self.downloaded as f64 * 100.0 / total as f64
+            ),
+            _ => format!("Downloaded {} bytes", self.downloaded),
+        };
+        if self.paused {
+            line.push_str(", paused");
+        }
+        line
+    }
+}
+
+/// Shared between the download and the http handlers that pause or abort it
+#[derive(Debug, Default)]
+pub struct StreamControl {
+    pub abort_requested: AtomicBool,
+    pub pause_requested: AtomicBool,
+    pub progress: Mutex<StreamProgress>,
+}
+
+enum Compression {
+    None,
+    Gzip,
+    Bzip2,
+    Lz4,
+}
+
+/// Same formats pipe_downloader unpacks, told apart by the URL suffix
+fn compression(url: &str) -> anyhow::Result<Compression> {
+    let path = url.split(['?', '#']).next().unwrap_or(url);
+    if path.ends_with(".tar") {
+        Ok(Compression::None)
+    } else if path.ends_with(".tar.gz") || path.ends_with(".tgz") {
+        Ok(Compression::Gzip)
+    } else if path.ends_with(".tar.bz2") {
+        Ok(Compression::Bzip2)
+    } else if path.ends_with(".tar.lz4") {
+        Ok(Compression::Lz4)
+    } else {
+        Err(anyhow::anyhow!("Unsupported archive format: {}", url))
+    }
+}
+
+/// Fails for archives the verified stream cannot unpack, checked before anything is touched
+pub fn check_format(url: &str) -> anyhow::Result<()> {
+    compression(url).map(|_| ())
+}
+
+/// Hashes every compressed byte on its way to the decoder, so the digest covers
+/// exactly the data that is unpacked
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: Hasher,
+    control: &'a StreamControl,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.control.pause_requested.load(Ordering::SeqCst)
+            && !self.control.abort_requested.load(Ordering::SeqCst)
+        {
+            self.control.progress.lock().unwrap().paused = true;
+            thread::sleep(Duration::from_millis(200));
+        }
+        self.control.progress.lock().unwrap().paused = false;
+        if self.control.abort_requested.load(Ordering::SeqCst) {
+            return Err(io::Error::other("Download aborted"));
+        }
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.control.progress.lock().unwrap().downloaded += read as u64;
+        Ok(read)
+    }
+}
+
+/// Called with the error and the byte offset when the connection breaks mid-stream,
+/// returns whether to reconnect and continue from that offset
+pub type OnInterrupted<'a> = &'a dyn Fn(&io::Error, u64) -> bool;
+
+/// Response body that reconnects with a `Range` request when the connection breaks,
+/// so the decoder and hasher reading from it see one continuous stream
+struct ResumingReader<'a> {
+    client: Client,
+    url: &'a str,
+    response: Response,
+    offset: u64,
+    /// ETag or Last-Modified of the first response, a resumed request must not mix in
+    /// the bytes of a newer archive
+    validator: Option<String>,
+    on_interrupted: OnInterrupted<'a>,
+}
+
+impl ResumingReader<'_> {
+    /// `Ok(None)` when the server does not continue at `offset`, resuming is not possible then
+    fn reconnect(&self) -> io::Result<Option<Response>> {
+        let mut request = self
+            .client
+            .get(self.url)
+            .header(RANGE, format!("bytes={}-", self.offset));
+        if let Some(validator) = &self.validator {
+            request = request.header(IF_RANGE, validator);
+        }
+        let response = request
+            .send()
+            .and_then(|r| r.error_for_status())
+            .map_err(io::Error::other)?;
+        let expected_range = format!("bytes {}-", self.offset);
+        let continues = response.status() == StatusCode::PARTIAL_CONTENT
+            && response
+                .headers()
+                .get(CONTENT_RANGE)
+                .and_then(|range| range.to_str().ok())
+                .map(|range| range.starts_with(&expected_range))
+                .unwrap_or(false);
+        Ok(continues.then_some(response))
+    }
+}
+
+impl Read for ResumingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut error = match self.response.read(buf) {
+            Ok(read) => {
+                self.offset += read as u64;
+                return Ok(read);
+            }
+            Err(e) => e,
+        };
+        loop {
+            if !(self.on_interrupted)(&error, self.offset) {
+                return Err(error);
+            }
+            log::info!("Resuming {} at byte {}", self.url, self.offset);
+            match self.reconnect() {
+                Ok(Some(response)) => {
+                    self.response = response;
+                    return self.read(buf);
+                }
+                Ok(None) => {
+                    return Err(io::Error::other(format!(
+                        "server cannot resume at byte {}: {}",
+                        self.offset, error
+                    )))
+                }
+                Err(e) => error = e,
+            }
+        }
+    }
+}
+
+/// Downloads `url` and unpacks it into `output_dir` in a single stream,
+/// returning the hex digest of the compressed archive. A broken connection is
+/// resumed at the byte it stopped at for as long as `on_interrupted` allows.
+pub fn download_and_hash(
+    url: &str,
+    output_dir: &Path,
+    algorithm: ChecksumAlgorithm,
+    control: &StreamControl,
+    on_interrupted: OnInterrupted<'_>,
+) -> anyhow::Result<String> {
+    let compression = compression(url)?;
+    control.progress.lock().unwrap().streaming = true;
+    let client = Client::builder().timeout(None).build()?;
+    let response = client
+        .get(url)
+        .send()
+        .and_then(|r| r.error_for_status())
+        .map_err(|e| anyhow::anyhow!("Failed to download {}: {}", url, e))?;
+    *control.progress.lock().unwrap() = StreamProgress {
+        streaming: true,
+        downloaded: 0,
+        total: response.content_length(),
+        paused: false,
+    };
+    fs::create_dir_all(output_dir)
+        .map_err(|e| anyhow::anyhow!("Failed to create {}: {}", output_dir.display(), e))?;
+
+    let validator = [ETAG, LAST_MODIFIED]
+        .iter()
+        .find_map(|name| response.headers().get(name))
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let mut tap = HashingReader {
+        inner: ResumingReader {
+            client,
+            url,
+            response,
+            offset: 0,
+            validator,
+            on_interrupted,
+        },
+        hasher: Hasher::new(algorithm),
+        control,
+    };
+    log::info!("Downloading {} into {}", url, output_dir.display());
+    {
+        let decoder: Box<dyn Read + '_> = match compression {
+            Compression::None => Box::new(&mut tap),
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(&mut tap)),
+            Compression::Bzip2 => Box::new(bzip2::read::MultiBzDecoder::new(&mut tap)),
+            Compression::Lz4 => Box::new(lz4::Decoder::new(&mut tap)?),
+        };
+        let mut archive = tar::Archive::new(decoder);
+        archive.set_preserve_permissions(true);
+        archive
+            .unpack(output_dir)
+            .map_err(|e| anyhow::anyhow!("Failed to unpack {}: {}", url, e))?;
+    }
+    // whatever follows the end of the tar stream is part of the archive as well
+    io::copy(&mut tap, &mut io::sink())
+        .map_err(|e| anyhow::anyhow!("Failed to download {}: {}", url, e))?;
+    log::info!("Download finished");
+    Ok(tap.hasher.finalize_hex())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn accepts_formats_the_stream_unpacks() {
+        for url in [
+            "http://localhost/erigon.tar",
+            "http://localhost/erigon.tar.gz",
+            "http://localhost/erigon.tgz",
+            "http://localhost/erigon.tar.bz2",
+            "http://localhost/erigon.tar.lz4?token=1",
+        ] {
+            assert!(check_format(url).is_ok(), "{}", url);
+        }
+        assert!(check_format("http://localhost/erigon.tar.zst").is_err());
+        assert!(check_format("http://localhost/erigon.zip#.tar").is_err());
+    }
+
+    #[test]
+    fn describes_progress() {
+        let mut progress = StreamProgress {
+            streaming: true,
+            downloaded: 50,
+            total: Some(200),
+            paused: false,
+        };
+        assert_eq!(progress.human_line(), "Downloaded 50 of 200 bytes (25.0%)");
+        progress.total = None;
+        progress.paused = true;
+        assert_eq!(progress.human_line(), "Downloaded 50 bytes, paused");
+    }
+}
@@ -0,0 +1,228 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+use crate::config::Profile;
+use crate::history::TaskTrigger;
+use crate::stage::{Stage, StageRecord};
+use crate::update_task::RunState;
+
+const TASK_STATE_SUFFIX: &str = ".task.json";
+
+/// Snapshot of a running task, rewritten before every step so a restarted updater
+/// can tell where it stopped. Kept after the task ends, with a terminal stage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct TaskState {
+    pub profile_name: String,
+    pub profile: Profile,
+    pub trigger: TaskTrigger,
+    pub started_at: DateTime<Utc>,
+    pub stage: Stage,
+    pub stages: Vec<StageRecord>,
+    pub run_state: RunState,
+    pub progress: serde_json::Value,
+    pub updated_at: DateTime<Utc>,
+}
+
+/// One file per profile, tasks of different profiles may run at the same time
+pub fn task_state_path(state_dir: &Path, profile_name: &str) -> PathBuf {
+    state_dir.join(format!("{}{}", profile_name, TASK_STATE_SUFFIX))
+}
+
+impl TaskState {
+    /// Written to a temporary file first, so a crash never leaves a truncated state
+    pub fn save(&self) -> anyhow::Result<()> {
+        let path = task_state_path(&self.profile.state_dir, &self.profile_name);
+        fs::create_dir_all(&self.profile.state_dir).map_err(|e| {
+            anyhow::anyhow!(
+                "Failed to create {}: {}",
+                self.profile.state_dir.display(),
+                e
+            )
+        })?;
+        let tmp = path.with_extension("json.tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", tmp.display(), e))?;
+        fs::rename(&tmp, &path)
+            .map_err(|e| anyhow::anyhow!("Failed to write {}: {}", path.display(), e))
+    }
+
+    fn load(path: &Path) -> anyhow::Result<Option<Self>> {
+        if !path.exists() {
+            return Ok(None);
+        }
+        let contents = fs::read_to_string(path)
+            .map_err(|e| anyhow::anyhow!("Failed to read {}: {}", path.display(), e))?;
+        let mut state: TaskState = serde_json::from_str(&contents)
+            .map_err(|e| anyhow::anyhow!("Failed to parse {}: {}", path.display(), e))?;
+        state.profile.name = state.profile_name.clone();
+        Ok(Some(state))
+    }
+}
+
+/// Renames a state file that cannot be read, so it is neither lost nor picked up again
+fn set_aside(path: &Path, error: &anyhow::Error) {
+    let aside = path.with_extension(format!("json.invalid-{}", Utc::now().timestamp()));
+    match fs::rename(path, &aside) {
+        Ok(()) => log::error!(
+            "Ignoring task state {}, moved to {}: {}",
+            path.display(),
+            aside.display(),
+            error
+        ),
+        Err(e) => log::error!(
+            "Ignoring task state {}: {}, failed to move it aside: {}",
+            path.display(),
+            error,
+            e
+        ),
+    }
+}
+
+/// Looks for tasks that did not reach a terminal stage in any of `state_dirs`.
+/// Unreadable directories and state files are logged and skipped, they must not keep
+/// the updater from starting.
+pub fn find_interrupted_tasks(state_dirs: &[PathBuf]) -> Vec<TaskState> {
+    let mut interrupted: Vec<TaskState> = Vec::new();
+    for state_dir in state_dirs {
+        if !state_dir.exists() {
+            continue;
+        }
+        let entries = match fs::read_dir(state_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                log::error!("Failed to read {}: {}", state_dir.display(), e);
+                continue;
+            }
+        };
+        for entry in entries {
+            let path = match entry {
+                Ok(entry) => entry.path(),
+                Err(e) => {
+                    log::error!("Failed to read {}: {}", state_dir.display(), e);
+                    continue;
+                }
+            };
+            let is_state_file = path
+                .file_name()
+                .map(|name| name.to_string_lossy().ends_with(TASK_STATE_SUFFIX))
+                .unwrap_or(false);
+            if !is_state_file {
+                continue;
+            }
+            let state = match TaskState::load(&path) {
+                Ok(Some(state)) if !state.stage.is_terminal() => state,
+                Ok(_) => continue,
+                Err(e) => {
+                    set_aside(&path, &e);
+                    continue;
+                }
+            };
+            // a profile whose state_dir changed can leave a file in both, the newest one counts
+            match interrupted
+                .iter_mut()
+                .find(|other| other.profile_name == state.profile_name)
+            {
+                Some(other) if other.updated_at < state.updated_at => *other = state,
+                Some(_) => {}
+                None => interrupted.push(state),
+            }
+        }
+    }
+    interrupted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "pipe_updater_state_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn state(name: &str, state_dir: &Path, stage: Stage) -> TaskState {
+        let mut profile: Profile = toml::from_str(&format!(
+            "archive_url = \"http://localhost/{}.tar.lz4\"\n\
+             output_dir = \"/data/{}\"\n\
+             state_dir = \"{}\"",
+            name,
+            name,
+            state_dir.display()
+        ))
+        .unwrap();
+        profile.name = name.into();
+        TaskState {
+            profile_name: name.into(),
+            profile,
+            trigger: TaskTrigger::Api,
+            started_at: Utc::now(),
+            stage,
+            stages: vec![],
+            run_state: RunState::default(),
+            progress: serde_json::Value::Null,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn finds_only_unfinished_tasks() {
+        let dir = temp_dir("unfinished");
+        state("erigon", &dir, Stage::Downloading).save().unwrap();
+        state("beacon", &dir, Stage::Finished).save().unwrap();
+        let found = find_interrupted_tasks(&[dir.clone(), dir.join("missing")]);
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].profile_name, "erigon");
+        assert_eq!(found[0].profile.name, "erigon");
+    }
+
+    #[test]
+    fn sets_aside_unreadable_state_files() {
+        let dir = temp_dir("unreadable");
+        let path = task_state_path(&dir, "erigon");
+        fs::write(&path, b"{ not json").unwrap();
+        state("beacon", &dir, Stage::StoppingServices)
+            .save()
+            .unwrap();
+        let found = find_interrupted_tasks(std::slice::from_ref(&dir));
+        let aside = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|entry| entry.ok())
+            .map(|entry| entry.file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with("erigon.task.json.invalid-"))
+            .count();
+        let still_there = path.exists();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].profile_name, "beacon");
+        assert!(!still_there);
+        assert_eq!(aside, 1);
+    }
+
+    #[test]
+    fn newest_state_of_a_profile_wins() {
+        let old_dir = temp_dir("old");
+        let new_dir = temp_dir("new");
+        let mut old = state("erigon", &old_dir, Stage::Downloading);
+        old.updated_at = Utc::now() - chrono::Duration::hours(1);
+        old.save().unwrap();
+        state("erigon", &new_dir, Stage::ChangingOwnership)
+            .save()
+            .unwrap();
+        let found = find_interrupted_tasks(&[new_dir.clone(), old_dir.clone()]);
+        fs::remove_dir_all(&old_dir).unwrap();
+        fs::remove_dir_all(&new_dir).unwrap();
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].stage, Stage::ChangingOwnership);
+    }
+}
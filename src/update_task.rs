@@ -0,0 +1,901 @@
+use std::collections::HashMap;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use pipe_downloader::pipe_downloader::{PipeDownloader, PipeDownloaderOptions};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+use crate::backup::Backup;
+use crate::checksum::{resolve_expected_checksum, ChecksumError, ChecksumResult, ExpectedChecksum};
+use crate::command::run_command;
+use crate::config::{InterruptedTaskPolicy, Profile, RecoveryPolicy};
+use crate::download::{download_with_retry, DownloadAttempt};
+use crate::guard::check_removal_paths;
+use crate::health::{verify_services, HealthCheckError, HealthCheckResult};
+use crate::history::{append_history, HistoryEntry, TaskTrigger};
+use crate::hooks::{run_hooks, HookResult, HookWhen};
+use crate::installed::{installed_version_path, InstalledVersion};
+use crate::ownership::{change_ownership, ChownProgress};
+use crate::pipeline::Step;
+use crate::remote::{fetch_archive_info, ArchiveInfo};
+use crate::removal::{expand_removal_targets, remove_paths, RemovalProgress};
+use crate::services::{
+    for_each_parallel, start_groups, stop_groups, stop_service, ServiceStopResult,
+};
+use crate::signature::{verify_signed_checksum, SignatureResult};
+use crate::stage::{Stage, StageTracker};
+use crate::stream::StreamControl;
+use crate::task_state::TaskState;
+
+pub struct UpdateTask {
+    profile: Profile,
+    is_running: Arc<Mutex<bool>>,
+    error_message: Arc<Mutex<Option<String>>>,
+    shared: TaskShared,
+    start: Option<TaskStart>,
+}
+
+/// Where the worker thread picks up
+enum TaskStart {
+    Fresh,
+    /// Runs the pipeline from `RunState::next_step` of an interrupted task
+    Resume(RunState),
+    /// Cleans up after an interrupted task as if its current step had failed
+    Recover(RunState),
+}
+
+/// Task state written by the worker thread and read by the http handlers
+#[derive(Clone)]
+struct TaskShared {
+    downloader: Arc<Mutex<PipeDownloader>>,
+    stage: Arc<Mutex<StageTracker>>,
+    hook_results: Arc<Mutex<Vec<HookResult>>>,
+    health_results: Arc<Mutex<Vec<HealthCheckResult>>>,
+    download_attempts: Arc<Mutex<Vec<DownloadAttempt>>>,
+    service_stops: Arc<Mutex<Vec<ServiceStopResult>>>,
+    chown_progress: Arc<Mutex<ChownProgress>>,
+    removal_progress: Arc<Mutex<RemovalProgress>>,
+    checksum: Arc<Mutex<Option<ChecksumResult>>>,
+    signature: Arc<Mutex<Option<SignatureResult>>>,
+    stream: Arc<StreamControl>,
+    trigger: TaskTrigger,
+    started_at: DateTime<Utc>,
+}
+
+impl TaskShared {
+    fn progress_json(&self, error_message: Option<String>) -> serde_json::Value {
+        let stage = self.stage.lock().unwrap();
+        let stream = self.stream.progress.lock().unwrap().clone();
+        // a verified download bypasses pipe_downloader, its progress would stay empty
+        let download_progress = if stream.streaming {
+            json!(stream)
+        } else {
+            self.downloader.lock().unwrap().get_progress_json()
+        };
+        json!({
+            "stage": stage.current(),
+            "stages": stage.history(),
+            "serviceStops": *self.service_stops.lock().unwrap(),
+            "removal": *self.removal_progress.lock().unwrap(),
+            "ownership": *self.chown_progress.lock().unwrap(),
+            "hooks": *self.hook_results.lock().unwrap(),
+            "healthChecks": *self.health_results.lock().unwrap(),
+            "downloadAttempts": *self.download_attempts.lock().unwrap(),
+            "checksum": *self.checksum.lock().unwrap(),
+            "stream": stream,
+            "signature": *self.signature.lock().unwrap(),
+            "errorMessage": error_message,
+            "downloadProgress": download_progress
+        })
+    }
+}
+
+/// State collected while the pipeline runs, used to clean up after a failure
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RunState {
+    /// Index into the profile steps of the step that is about to run or running
+    next_step: usize,
+    backup: Option<Backup>,
+    /// Services stopped by the task and not started again yet
+    stopped_services: Vec<String>,
+    /// Set once a step that may destroy the old data has started
+    data_touched: bool,
+    /// Set once `start_services` has started, the services may be running on the new data
+    #[serde(default)]
+    services_started: bool,
+    /// Metadata of the archive taken when the download started, recorded once the task finishes
+    archive_info: Option<ArchiveInfo>,
+}
+
+impl RunState {
+    /// State is saved before each step, so whatever the interrupted step does may have happened
+    fn assume_started(&mut self, step: &Step, profile: &Profile) {
+        match step {
+            Step::StopServices => {
+                for service in &profile.services_to_stop {
+                    if !self.stopped_services.contains(service) {
+                        self.stopped_services.push(service.clone());
+                    }
+                }
+            }
+            Step::RemovePaths | Step::Download => self.data_touched = true,
+            Step::StartServices => self.services_started = true,
+            _ => {}
+        }
+    }
+}
+
+/// Failing to write the state only loses crash recovery, the task itself goes on
+fn save_state(profile: &Profile, shared: &TaskShared, run_state: &RunState) {
+    let (stage, stages) = {
+        let stage = shared.stage.lock().unwrap();
+        (stage.current(), stage.history().to_vec())
+    };
+    let state = TaskState {
+        profile_name: profile.name.clone(),
+        profile: profile.clone(),
+        trigger: shared.trigger,
+        started_at: shared.started_at,
+        stage,
+        stages,
+        run_state: run_state.clone(),
+        progress: shared.progress_json(None),
+        updated_at: Utc::now(),
+    };
+    if let Err(e) = state.save() {
+        log::error!("Failed to save task state: {}", e);
+    }
+}
+
+fn stop_services(
+    profile: &Profile,
+    results: &Arc<Mutex<Vec<ServiceStopResult>>>,
+    run_state: &mut RunState,
+) -> anyhow::Result<()> {
+    for group in stop_groups(&profile.services_to_stop, &profile.service_dependencies)? {
+        log::info!("Stopping services: {:?}", group);
+        for service_to_stop in &group {
+            if !run_state.stopped_services.contains(service_to_stop) {
+                run_state.stopped_services.push(service_to_stop.clone());
+            }
+        }
+        for_each_parallel(&group, |service| {
+            stop_service(service, &profile.service_stop, results)
+        })?;
+    }
+    Ok(())
+}
+
+fn start_services(
+    services_to_start: &[String],
+    dependencies: &HashMap<String, Vec<String>>,
+    run_state: &mut RunState,
+) -> anyhow::Result<()> {
+    for group in start_groups(services_to_start, dependencies)? {
+        log::info!("Starting services: {:?}", group);
+        let started = Mutex::new(Vec::new());
+        let result = for_each_parallel(&group, |service_to_start| {
+            log::info!("Starting service: {}", service_to_start);
+            match systemctl::restart(service_to_start) {
+                Ok(_) => {
+                    started.lock().unwrap().push(service_to_start.to_string());
+                    Ok(())
+                }
+                Err(e) => {
+                    log::error!("Error restarting service {}: {}", service_to_start, e);
+                    Err(anyhow::anyhow!(
+                        "Error starting service {}: {}",
+                        service_to_start,
+                        e
+                    ))
+                }
+            }
+        });
+        let started = started.into_inner().unwrap();
+        run_state.stopped_services.retain(|s| !started.contains(s));
+        result?;
+    }
+    Ok(())
+}
+
+fn run_step_command(command: &[String], timeout_secs: Option<u64>) -> anyhow::Result<()> {
+    let output = run_command(command, timeout_secs.map(Duration::from_secs))?;
+    if !output.success() {
+        log::error!("{}", output.describe_failure());
+        return Err(anyhow::anyhow!(output.describe_failure()));
+    }
+    Ok(())
+}
+
+/// Runs `action` surrounded by the hooks registered for `stage`
+fn run_hooked(
+    profile: &Profile,
+    shared: &TaskShared,
+    stage: Stage,
+    action: impl FnOnce() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    run_hooks(&profile.hooks, stage, HookWhen::Pre, &shared.hook_results)?;
+    action()?;
+    run_hooks(&profile.hooks, stage, HookWhen::Post, &shared.hook_results)
+}
+
+/// Enters `stage` and runs `action` surrounded by the hooks registered for it
+fn run_stage(
+    profile: &Profile,
+    shared: &TaskShared,
+    stage: Stage,
+    action: impl FnOnce() -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    shared.stage.lock().unwrap().enter(stage)?;
+    run_hooked(profile, shared, stage, action)
+}
+
+/// The signature only has to be checked when a remaining step touches the data
+fn verifies_signature(profile: &Profile, remaining: &[Step]) -> bool {
+    !profile.signature_public_keys.is_empty()
+        && remaining
+            .iter()
+            .any(|step| matches!(step, Step::RemovePaths | Step::Download))
+}
+
+/// Stages `run_pipeline` enters when it starts at `first_step`, in order
+fn pipeline_stages(profile: &Profile, first_step: usize) -> Vec<Stage> {
+    let remaining = profile.steps.get(first_step..).unwrap_or_default();
+    let mut stages = Vec::new();
+    if verifies_signature(profile, remaining) {
+        stages.push(Stage::VerifyingSignature);
+    }
+    for step in remaining {
+        stages.push(step.stage());
+        if *step == Step::StartServices && !profile.health_checks.is_empty() {
+            stages.push(Stage::Verifying);
+        }
+    }
+    stages
+}
+
+/// Checks the signed checksum before any step runs, so nothing is stopped or removed
+/// for an archive that is not signed by a trusted key
+fn verify_signature(profile: &Profile, shared: &TaskShared) -> anyhow::Result<()> {
+    let result = verify_signed_checksum(&profile.archive_url, &profile.signature_public_keys)?;
+    if let Some(configured) = &profile.checksum {
+        if ExpectedChecksum::parse(configured)? != result.checksum {
+            return Err(anyhow::anyhow!(
+                "Configured checksum {} differs from signed checksum sha256:{}",
+                configured,
+                result.checksum.hex
+            ));
+        }
+    }
+    *shared.checksum.lock().unwrap() = Some(ChecksumResult {
+        expected: result.checksum.clone(),
+        actual: None,
+        verified: None,
+    });
+    *shared.signature.lock().unwrap() = Some(result);
+    Ok(())
+}
+
+fn run_pipeline(
+    profile: &Profile,
+    shared: &TaskShared,
+    run_state: &mut RunState,
+) -> anyhow::Result<()> {
+    let remaining = profile.steps.get(run_state.next_step..).unwrap_or_default();
+    if verifies_signature(profile, remaining) {
+        run_stage(profile, shared, Stage::VerifyingSignature, || {
+            verify_signature(profile, shared)
+        })?;
+    }
+    for (idx, step) in profile.steps.iter().enumerate().skip(run_state.next_step) {
+        run_state.next_step = idx;
+        // saved once the stage is entered, so the state file shows the step that may have run
+        shared.stage.lock().unwrap().enter(step.stage())?;
+        save_state(profile, shared, run_state);
+        run_hooked(profile, shared, step.stage(), || {
+            run_step(profile, shared, step, run_state)
+        })?;
+        if *step == Step::StartServices && !profile.health_checks.is_empty() {
+            run_stage(profile, shared, Stage::Verifying, || {
+                verify_services(&profile.health_checks, &shared.health_results)
+            })?;
+        }
+    }
+    run_state.next_step = profile.steps.len();
+    Ok(())
+}
+
+fn run_step(
+    profile: &Profile,
+    shared: &TaskShared,
+    step: &Step,
+    run_state: &mut RunState,
+) -> anyhow::Result<()> {
+    if matches!(step, Step::RemovePaths | Step::Download) {
+        run_state.data_touched = true;
+    }
+    if *step == Step::StartServices {
+        run_state.services_started = true;
+    }
+    match step {
+        Step::StopServices => stop_services(profile, &shared.service_stops, run_state)?,
+        Step::RemovePaths => {
+            // checked again, the file system may have changed since the task was created
+            let targets = expand_removal_targets(&profile.delete_dirs)?;
+            check_removal_paths(&targets, &profile.removal_roots)?;
+            match &profile.backup_dir {
+                Some(backup_dir) => {
+                    run_state.backup = Some(Backup::move_aside(&targets, backup_dir)?)
+                }
+                None => remove_paths(&targets, &profile.delete_keep, &shared.removal_progress)?,
+            }
+        }
+        Step::Download => {
+            run_state.archive_info = match fetch_archive_info(&profile.archive_url) {
+                Ok(info) => Some(info),
+                Err(e) => {
+                    log::warn!(
+                        "Cannot read archive metadata, it will not be recorded: {}",
+                        e
+                    );
+                    None
+                }
+            };
+            // already set when the checksum came with a verified signature
+            if shared.checksum.lock().unwrap().is_none() {
+                let expected = resolve_expected_checksum(
+                    &profile.archive_url,
+                    &profile.checksum,
+                    profile.checksum_sidecar,
+                )?;
+                *shared.checksum.lock().unwrap() = expected.map(|expected| ChecksumResult {
+                    expected,
+                    actual: None,
+                    verified: None,
+                });
+            }
+            download_with_retry(
+                &profile.archive_url,
+                &profile.output_dir,
+                &profile.download_retry,
+                &shared.downloader,
+                &shared.download_attempts,
+                &shared.checksum,
+                &shared.stream,
+            )?
+        }
+        Step::Chown => {
+            let owner = match (&profile.target_user, &profile.target_group) {
+                (Some(user), Some(group)) => Some((user.as_str(), group.as_str())),
+                _ => None,
+            };
+            change_ownership(
+                owner,
+                &profile.change_owner_paths,
+                &profile.permissions,
+                &shared.chown_progress,
+            )?
+        }
+        Step::StartServices => start_services(
+            &profile.services_to_stop,
+            &profile.service_dependencies,
+            run_state,
+        )?,
+        Step::RunCommand {
+            command,
+            timeout_secs,
+        } => run_step_command(command, *timeout_secs)?,
+        Step::Wait { seconds } => thread::sleep(Duration::from_secs(*seconds)),
+    }
+    Ok(())
+}
+
+/// Brings back services stopped by a failed task, according to the profile recovery policy.
+/// Services are never started on data that failed checksum verification.
+fn recover_services(
+    profile: &Profile,
+    shared: &TaskShared,
+    run_state: &mut RunState,
+    data_intact: bool,
+    data_rejected: bool,
+) -> anyhow::Result<()> {
+    let stage = &shared.stage;
+    stage.lock().unwrap().enter(Stage::RecoveringServices)?;
+    save_state(profile, shared, run_state);
+    let skip_reason = match profile.restart_services_on_failure {
+        _ if data_rejected => Some("unpacked data failed checksum verification"),
+        RecoveryPolicy::Never => Some("restart_services_on_failure is set to never"),
+        RecoveryPolicy::IfDataIntact if !data_intact => Some("data is not intact"),
+        _ => None,
+    };
+    if let Some(skip_reason) = skip_reason {
+        log::warn!(
+            "Not restarting stopped services {:?}: {}",
+            run_state.stopped_services,
+            skip_reason
+        );
+        stage.lock().unwrap().skip_current(skip_reason);
+        return Ok(());
+    }
+    let services = run_state.stopped_services.clone();
+    log::info!(
+        "Restarting services stopped by failed update: {:?}",
+        services
+    );
+    start_services(&services, &profile.service_dependencies, run_state)
+}
+
+/// Failing to write the record does not fail the finished update, the next run just won't be skipped
+fn record_installed_version(
+    profile: &Profile,
+    shared: &TaskShared,
+    archive_info: Option<ArchiveInfo>,
+) {
+    let checksum = shared
+        .checksum
+        .lock()
+        .unwrap()
+        .as_ref()
+        .filter(|c| c.verified == Some(true))
+        .map(|c| format!("{}:{}", c.expected.algorithm, c.expected.hex));
+    let record = InstalledVersion {
+        profile: profile.name.clone(),
+        archive_url: profile.archive_url.clone(),
+        etag: archive_info.as_ref().and_then(|i| i.etag.clone()),
+        last_modified: archive_info.as_ref().and_then(|i| i.last_modified.clone()),
+        size: archive_info.as_ref().and_then(|i| i.size),
+        checksum,
+        completed_at: Utc::now(),
+    };
+    let path = installed_version_path(profile);
+    match record.save(&path) {
+        Ok(()) => log::info!("Recorded installed version in {}", path.display()),
+        Err(e) => log::error!("Failed to record installed version: {}", e),
+    }
+}
+
+/// Cleans up after the pipeline, restoring the old state if it failed
+fn finish_task(
+    profile: &Profile,
+    shared: &TaskShared,
+    run_state: &mut RunState,
+    result: anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    let stage = &shared.stage;
+    if let Err(e) = result {
+        stage.lock().unwrap().fail_current(&e.to_string());
+        let health_check_failed = e.downcast_ref::<HealthCheckError>().is_some();
+        let checksum_failed = e.is::<ChecksumError>();
+        let mut errors = vec![e.to_string()];
+        let mut data_intact = !run_state.data_touched;
+        if let Some(backup) = run_state.backup.clone() {
+            if health_check_failed || run_state.services_started {
+                // services may already be running on the new data, swapping the old data
+                // back in under them is left to the operator
+                log::warn!("Keeping backup of old data in {}", backup.root().display());
+                errors.push(format!("old data kept in {}", backup.root().display()));
+            } else {
+                stage.lock().unwrap().enter(Stage::RestoringBackup)?;
+                save_state(profile, shared, run_state);
+                match backup.restore() {
+                    Ok(()) => {
+                        run_state.backup = None;
+                        data_intact = true;
+                    }
+                    Err(restore_err) => {
+                        log::error!("Failed to restore backup: {}", restore_err);
+                        stage.lock().unwrap().fail_current(&restore_err.to_string());
+                        errors.push(format!("restoring backup failed: {}", restore_err));
+                    }
+                }
+            }
+        }
+        let data_rejected = checksum_failed && !data_intact;
+        if data_rejected {
+            errors.push(format!(
+                "unverified data left in {}",
+                profile.output_dir.display()
+            ));
+        }
+        // also covers services left stopped by a `start_services` step that failed partway
+        if !run_state.stopped_services.is_empty() {
+            if let Err(recover_err) =
+                recover_services(profile, shared, run_state, data_intact, data_rejected)
+            {
+                log::error!("Failed to restart services: {}", recover_err);
+                stage.lock().unwrap().fail_current(&recover_err.to_string());
+                errors.push(format!("restarting services failed: {}", recover_err));
+            }
+        }
+        let terminal = if health_check_failed {
+            Stage::VerificationFailed
+        } else {
+            Stage::Failed
+        };
+        stage.lock().unwrap().enter(terminal)?;
+        return Err(anyhow::anyhow!(errors.join(", ")));
+    }
+    if let Some(backup) = run_state.backup.clone() {
+        stage.lock().unwrap().enter(Stage::RemovingBackup)?;
+        save_state(profile, shared, run_state);
+        if let Err(e) = backup.discard() {
+            log::error!("Update finished, but backup was not removed: {}", e);
+        }
+        run_state.backup = None;
+    }
+    stage.lock().unwrap().enter(Stage::Finished)?;
+    if profile.steps.contains(&Step::Download) {
+        record_installed_version(profile, shared, run_state.archive_info.clone());
+    }
+    Ok(())
+}
+
+/// Failing to write the entry does not change the outcome of the task
+fn record_history(profile: &Profile, shared: &TaskShared, error_message: Option<String>) {
+    let (stage, stages) = {
+        let stage = shared.stage.lock().unwrap();
+        (stage.current(), stage.history().to_vec())
+    };
+    let bytes_downloaded = shared
+        .download_attempts
+        .lock()
+        .unwrap()
+        .iter()
+        .filter_map(|attempt| attempt.bytes_downloaded)
+        .sum();
+    let entry = HistoryEntry {
+        id: 0,
+        profile: profile.name.clone(),
+        trigger: shared.trigger,
+        archive_url: profile.archive_url.clone(),
+        started_at: shared.started_at,
+        finished_at: Utc::now(),
+        stage,
+        stages,
+        bytes_downloaded,
+        error_message,
+    };
+    match append_history(entry) {
+        Ok(entry) => log::info!("Recorded task {} in history", entry.id),
+        Err(e) => log::error!("Failed to record task history: {}", e),
+    }
+}
+
+fn update_task_main(
+    profile: &Profile,
+    shared: &TaskShared,
+    start: TaskStart,
+) -> anyhow::Result<()> {
+    let (mut run_state, result) = match start {
+        TaskStart::Fresh => {
+            let mut run_state = RunState::default();
+            let result = run_pipeline(profile, shared, &mut run_state);
+            (run_state, result)
+        }
+        TaskStart::Resume(mut run_state) => {
+            log::info!(
+                "Resuming interrupted task from step {}",
+                run_state.next_step
+            );
+            shared
+                .stage
+                .lock()
+                .unwrap()
+                .set_pipeline(pipeline_stages(profile, run_state.next_step));
+            let result = run_pipeline(profile, shared, &mut run_state);
+            (run_state, result)
+        }
+        TaskStart::Recover(run_state) => {
+            log::info!("Recovering after interrupted task");
+            let result = Err(anyhow::anyhow!(
+                "Task was interrupted by a restart of the updater"
+            ));
+            (run_state, result)
+        }
+    };
+    let result = finish_task(profile, shared, &mut run_state, result);
+    save_state(profile, shared, &run_state);
+    result
+}
+
+impl UpdateTask {
+    pub fn new(profile: Profile, trigger: TaskTrigger) -> Self {
+        let downloader = PipeDownloader::new(
+            &profile.archive_url,
+            &profile.output_dir,
+            PipeDownloaderOptions::from_env(),
+        );
+        let tracker = StageTracker::new(pipeline_stages(&profile, 0));
+        Self {
+            profile,
+            is_running: Arc::new(Mutex::new(false)),
+            error_message: Arc::new(Mutex::new(None)),
+            shared: TaskShared {
+                downloader: Arc::new(Mutex::new(downloader)),
+                stage: Arc::new(Mutex::new(tracker)),
+                hook_results: Arc::new(Mutex::new(Vec::new())),
+                health_results: Arc::new(Mutex::new(Vec::new())),
+                download_attempts: Arc::new(Mutex::new(Vec::new())),
+                service_stops: Arc::new(Mutex::new(Vec::new())),
+                chown_progress: Arc::new(Mutex::new(ChownProgress::default())),
+                removal_progress: Arc::new(Mutex::new(RemovalProgress::default())),
+                checksum: Arc::new(Mutex::new(None)),
+                signature: Arc::new(Mutex::new(None)),
+                stream: Arc::new(StreamControl::default()),
+                trigger,
+                started_at: Utc::now(),
+            },
+            start: Some(TaskStart::Fresh),
+        }
+    }
+
+    /// Task for the state left behind by an updater that was stopped mid-update,
+    /// picked up according to the profile `interrupted_task` policy
+    pub fn from_interrupted(state: TaskState) -> Self {
+        let mut task = Self::new(state.profile, state.trigger);
+        task.shared.started_at = state.started_at;
+        let mut tracker = StageTracker::restore(state.stage, state.stages);
+        tracker.fail_current("Interrupted by a restart of the updater");
+        let interrupted_stage = tracker.current();
+        *task.shared.stage.lock().unwrap() = tracker;
+
+        let mut run_state = state.run_state;
+        if let Some(step) = task.profile.steps.get(run_state.next_step) {
+            run_state.assume_started(step, &task.profile);
+        }
+        let start = match (interrupted_stage, task.profile.interrupted_task) {
+            // the pipeline went through, only the backup was left to remove
+            (Stage::RemovingBackup, _) => {
+                run_state.next_step = task.profile.steps.len();
+                TaskStart::Resume(run_state)
+            }
+            (Stage::RestoringBackup | Stage::RecoveringServices, _) => {
+                TaskStart::Recover(run_state)
+            }
+            (_, InterruptedTaskPolicy::Resume) => TaskStart::Resume(run_state),
+            (_, InterruptedTaskPolicy::Recover) => TaskStart::Recover(run_state),
+        };
+        log::warn!(
+            "Found task of profile {} interrupted in stage {}",
+            task.profile.name,
+            interrupted_stage
+        );
+        task.start = Some(start);
+        task
+    }
+
+    pub fn profile(&self) -> &Profile {
+        &self.profile
+    }
+
+    pub fn pause(&self) {
+        self.shared
+            .stream
+            .pause_requested
+            .store(true, Ordering::SeqCst);
+        self.shared.downloader.lock().unwrap().pause_download();
+    }
+
+    pub fn resume(&self) {
+        self.shared
+            .stream
+            .pause_requested
+            .store(false, Ordering::SeqCst);
+        self.shared.downloader.lock().unwrap().resume_download();
+    }
+
+    pub fn abort(&self) {
+        self.shared
+            .stream
+            .abort_requested
+            .store(true, Ordering::SeqCst);
+        self.shared.downloader.lock().unwrap().signal_stop();
+    }
+
+    pub fn is_running(&self) -> bool {
+        *self.is_running.lock().unwrap()
+    }
+
+    pub fn get_progress_human_line(&self) -> String {
+        let stream = self.shared.stream.progress.lock().unwrap();
+        if stream.streaming {
+            return stream.human_line();
+        }
+        self.shared
+            .downloader
+            .lock()
+            .unwrap()
+            .get_progress_human_line()
+    }
+
+    pub fn get_progress(&self) -> serde_json::Value {
+        self.shared
+            .progress_json(self.error_message.lock().unwrap().clone())
+    }
+
+    pub fn run(&mut self) -> anyhow::Result<()> {
+        if *self.is_running.lock().unwrap() {
+            return Err(anyhow::anyhow!("Task is already running"));
+        }
+        *self.is_running.lock().unwrap() = true;
+        let profile = self.profile.clone();
+        let is_running = self.is_running.clone();
+        let error_message = self.error_message.clone();
+        let shared = self.shared.clone();
+        let start = self.start.take().unwrap_or(TaskStart::Fresh);
+
+        thread::spawn(move || {
+            match update_task_main(&profile, &shared, start) {
+                Ok(_) => {}
+                Err(e) => {
+                    shared.stage.lock().unwrap().fail(&e.to_string());
+                    *error_message.lock().unwrap() = Some(e.to_string());
+                    println!("Error running update task: {}", e);
+                }
+            };
+            record_history(&profile, &shared, error_message.lock().unwrap().clone());
+            *is_running.lock().unwrap() = false;
+        });
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::{Path, PathBuf};
+
+    use super::*;
+    use crate::stage::StageOutcome;
+
+    fn profile(policy: &str, state_dir: &Path) -> Profile {
+        let mut profile: Profile = toml::from_str(&format!(
+            "archive_url = \"http://localhost/erigon.tar.lz4\"\n\
+             output_dir = \"{}\"\n\
+             services_to_stop = [\"erigon\"]\n\
+             state_dir = \"{}\"\n\
+             interrupted_task = \"{}\"",
+            state_dir.join("erigon").display(),
+            state_dir.display(),
+            policy
+        ))
+        .unwrap();
+        profile.name = "erigon".into();
+        profile
+    }
+
+    /// State file contents of a task that entered the first `entered` stages of its pipeline,
+    /// followed by `extra`, with `next_step` about to run
+    fn interrupted(
+        profile: &Profile,
+        entered: usize,
+        extra: &[Stage],
+        run_state: RunState,
+    ) -> TaskState {
+        let mut tracker = StageTracker::new(pipeline_stages(profile, 0));
+        for stage in pipeline_stages(profile, 0).into_iter().take(entered) {
+            tracker.enter(stage).unwrap();
+        }
+        for stage in extra {
+            tracker.enter(*stage).unwrap();
+        }
+        TaskState {
+            profile_name: profile.name.clone(),
+            profile: profile.clone(),
+            trigger: TaskTrigger::Api,
+            started_at: Utc::now(),
+            stage: tracker.current(),
+            stages: tracker.history().to_vec(),
+            run_state,
+            progress: serde_json::Value::Null,
+            updated_at: Utc::now(),
+        }
+    }
+
+    fn at_step(next_step: usize) -> RunState {
+        RunState {
+            next_step,
+            ..RunState::default()
+        }
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("pipe_updater_task_{}_{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn recover_policy_treats_interrupted_step_as_failed() {
+        let profile = profile("recover", Path::new("/tmp"));
+        // stopped services, then removing old files
+        let task = UpdateTask::from_interrupted(interrupted(&profile, 2, &[], at_step(1)));
+        let stages = task.shared.stage.lock().unwrap().history().to_vec();
+        assert_eq!(stages[1].stage, Stage::RemovingOldFiles);
+        assert_eq!(stages[1].outcome, StageOutcome::Failed);
+        match task.start {
+            Some(TaskStart::Recover(run_state)) => {
+                assert!(run_state.data_touched);
+                assert!(!run_state.services_started);
+            }
+            _ => panic!("expected recovery"),
+        }
+    }
+
+    #[test]
+    fn resume_policy_runs_again_from_interrupted_step() {
+        let profile = profile("resume", Path::new("/tmp"));
+        let task = UpdateTask::from_interrupted(interrupted(&profile, 4, &[], at_step(3)));
+        match task.start {
+            Some(TaskStart::Resume(run_state)) => {
+                assert_eq!(run_state.next_step, 3);
+                assert!(run_state.data_touched);
+            }
+            _ => panic!("expected resume"),
+        }
+    }
+
+    #[test]
+    fn assumes_interrupted_service_steps_ran() {
+        let profile = profile("recover", Path::new("/tmp"));
+        let task = UpdateTask::from_interrupted(interrupted(&profile, 1, &[], at_step(0)));
+        match task.start {
+            Some(TaskStart::Recover(run_state)) => {
+                assert_eq!(run_state.stopped_services, vec!["erigon".to_string()]);
+            }
+            _ => panic!("expected recovery"),
+        }
+        let task = UpdateTask::from_interrupted(interrupted(&profile, 6, &[], at_step(5)));
+        match task.start {
+            Some(TaskStart::Recover(run_state)) => assert!(run_state.services_started),
+            _ => panic!("expected recovery"),
+        }
+    }
+
+    #[test]
+    fn interrupted_recovery_always_recovers() {
+        let profile = profile("resume", Path::new("/tmp"));
+        let state = interrupted(&profile, 3, &[Stage::RestoringBackup], at_step(3));
+        let task = UpdateTask::from_interrupted(state);
+        assert!(matches!(task.start, Some(TaskStart::Recover(_))));
+        let state = interrupted(&profile, 2, &[Stage::RecoveringServices], at_step(1));
+        let task = UpdateTask::from_interrupted(state);
+        assert!(matches!(task.start, Some(TaskStart::Recover(_))));
+    }
+
+    #[test]
+    fn interrupted_backup_removal_finishes_the_update() {
+        let base = temp_dir("removing_backup");
+        let data = base.join("erigon");
+        fs::create_dir_all(&data).unwrap();
+        fs::write(data.join("old"), b"old").unwrap();
+        let backup = Backup::move_aside(std::slice::from_ref(&data), &base.join("backup")).unwrap();
+        let backup_root = backup.root().to_path_buf();
+        fs::create_dir_all(&data).unwrap();
+
+        let profile = profile("recover", &base);
+        let steps = profile.steps.len();
+        let run_state = RunState {
+            next_step: steps - 1,
+            backup: Some(backup),
+            ..RunState::default()
+        };
+        let state = interrupted(&profile, steps, &[Stage::RemovingBackup], run_state);
+        let mut task = UpdateTask::from_interrupted(state);
+        let start = task.start.take().unwrap();
+        assert!(matches!(&start, TaskStart::Resume(run_state) if run_state.next_step == steps));
+
+        update_task_main(&task.profile, &task.shared, start).unwrap();
+        assert_eq!(task.shared.stage.lock().unwrap().current(), Stage::Finished);
+        assert!(!backup_root.exists());
+        assert!(installed_version_path(&profile).exists());
+        fs::remove_dir_all(&base).unwrap();
+    }
+}